@@ -0,0 +1,68 @@
+#[macro_use]
+extern crate criterion;
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, RayonThreadPool};
+use tempfile::TempDir;
+
+// Fills a store with many small generations, then measures how long reopening it takes
+// with a single thread and with all the cores rebuilding the index.
+fn open_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        max_segment_size: 1024 * 1024,
+        ..KvStoreOptions::default()
+    };
+    {
+        let store =
+            KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone()).unwrap();
+        smol::block_on(async {
+            for i in 0..(1 << 16) {
+                store
+                    .set(format!("key{}", i % (1 << 14)), format!("value{}", i))
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
+    let mut group = c.benchmark_group("open_bench");
+    let mut thread_counts = vec![1, num_cpus::get()];
+    thread_counts.dedup();
+    for &threads in &thread_counts {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(BenchmarkId::new("kvs", threads), &threads, |b, _| {
+            // every open starts a new generation, so each iteration opens a fresh copy
+            b.iter_batched(
+                || copy_dir(temp_dir.path()),
+                |dir| {
+                    pool.install(|| {
+                        KvStore::<RayonThreadPool>::open_with(dir.path(), 1, options.clone())
+                            .unwrap()
+                    });
+                    dir
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn copy_dir(from: &Path) -> TempDir {
+    let to = TempDir::new().unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, to.path().join(path.file_name().unwrap())).unwrap();
+    }
+    to
+}
+
+criterion_group!(benches, open_bench);
+criterion_main!(benches);
@@ -2,11 +2,14 @@
 //! kvs-client
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use clap::Clap;
+use smol::fs::File;
 
-use kvs::{KvsClient, Result, ADDRESS_FORMAT, DEFAULT_LISTENING_ADDRESS};
+use kvs::{KvsClient, Result, UserMeta, ADDRESS_FORMAT, DEFAULT_LISTENING_ADDRESS};
 
 #[derive(Clap, Debug)]
 #[clap(name = "kvs-client", version, author, about)]
@@ -21,6 +24,24 @@ enum Command {
     Get {
         #[clap(name = "KEY", about = "A string key")]
         key: String,
+        #[clap(long, about = "Also shows the metadata of the value")]
+        meta: bool,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "mget",
+        about = "Get the string values of the given string keys"
+    )]
+    GetMany {
+        #[clap(name = "KEY", about = "String keys", required = true)]
+        keys: Vec<String>,
         #[clap(
             long,
             about = "Sets the server address",
@@ -36,6 +57,54 @@ enum Command {
         key: String,
         #[clap(name = "VALUE", about = "The string value of the key")]
         value: String,
+        #[clap(long, about = "Attaches an opaque integer to the value")]
+        flags: Option<u32>,
+        #[clap(long, about = "Attaches a content type to the value")]
+        content_type: Option<String>,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "upload",
+        about = "Set the value of a string key to the contents of a file, streaming it"
+    )]
+    Upload {
+        #[clap(name = "KEY", about = "A string key")]
+        key: String,
+        #[clap(
+            name = "FILE",
+            about = "The file holding the value",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "download",
+        about = "Write the value of a given string key to a file, streaming it"
+    )]
+    Download {
+        #[clap(name = "KEY", about = "A string key")]
+        key: String,
+        #[clap(
+            name = "FILE",
+            about = "The file to write the value to",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
         #[clap(
             long,
             about = "Sets the server address",
@@ -58,6 +127,109 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[clap(name = "incr", about = "Increment the integer value of a given key")]
+    Incr {
+        #[clap(name = "KEY", about = "A string key")]
+        key: String,
+        #[clap(name = "DELTA", about = "The amount to increment", default_value = "1")]
+        delta: i64,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "decr", about = "Decrement the integer value of a given key")]
+    Decr {
+        #[clap(name = "KEY", about = "A string key")]
+        key: String,
+        #[clap(name = "DELTA", about = "The amount to decrement", default_value = "1")]
+        delta: i64,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "append", about = "Append a string to the value of a given key")]
+    Append {
+        #[clap(name = "KEY", about = "A string key")]
+        key: String,
+        #[clap(name = "VALUE", about = "The string to append")]
+        value: String,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "stats", about = "Show the statistics of the storage engine")]
+    Stats {
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "admin", about = "Run an administrative command")]
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Clap, Debug)]
+enum AdminCommand {
+    #[clap(name = "compact", about = "Compact the whole data")]
+    Compact {
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "flush", about = "Make all the previous writes durable")]
+    Flush {
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "rate-limit", about = "Limit the I/O rate of compactions")]
+    RateLimit {
+        #[clap(
+            name = "BYTES_PER_SEC",
+            about = "The maximum bytes per second, or 0 for no limit"
+        )]
+        bytes_per_sec: u64,
+        #[clap(
+            long,
+            about = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -70,7 +242,11 @@ fn main() {
 
 async fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get { key, addr } => {
+        Command::Get {
+            key,
+            meta: false,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr).await?;
             if let Some(value) = client.get(key).await? {
                 println!("{}", value);
@@ -78,14 +254,132 @@ async fn run(opt: Opt) -> Result<()> {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Get {
+            key,
+            meta: true,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr).await?;
+            if let Some((value, meta)) = client.get_with_meta(key).await? {
+                println!("{}", value);
+                println!("created_ms: {}", unix_millis(meta.created));
+                println!("updated_ms: {}", unix_millis(meta.updated));
+                println!("flags: {}", meta.user.flags);
+                if let Some(content_type) = meta.user.content_type {
+                    println!("content_type: {}", content_type);
+                }
+            } else {
+                println!("Key not found");
+            }
+        }
+        Command::GetMany { keys, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            for value in client.get_many(keys).await? {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        Command::Set {
+            key,
+            value,
+            flags: None,
+            content_type: None,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr).await?;
             client.set(key, value).await?;
         }
+        Command::Set {
+            key,
+            value,
+            flags,
+            content_type,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr).await?;
+            let meta = UserMeta {
+                flags: flags.unwrap_or_default(),
+                content_type,
+            };
+            client.set_with_meta(key, value, meta).await?;
+        }
+        Command::Upload { key, file, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            client.set_stream(key, File::open(file).await?).await?;
+        }
+        Command::Download { key, file, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            let found = client.get_stream(key, File::create(&file).await?).await?;
+            if !found {
+                smol::fs::remove_file(file).await?;
+                println!("Key not found");
+            }
+        }
         Command::Remove { key, addr } => {
             let mut client = KvsClient::connect(addr).await?;
             client.remove(key).await?;
         }
+        Command::Incr { key, delta, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            println!("{}", client.incr(key, delta).await?);
+        }
+        Command::Decr { key, delta, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            println!("{}", client.decr(key, delta).await?);
+        }
+        Command::Append { key, value, addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            println!("{}", client.append(key, value).await?);
+        }
+        Command::Stats { addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            let stats = client.stats().await?;
+            println!("keys: {}", stats.keys);
+            println!("live_bytes: {}", stats.live_bytes);
+            println!("uncompacted_bytes: {}", stats.uncompacted_bytes);
+            println!("generations: {}", stats.generations);
+            println!("compactions: {}", stats.compactions);
+            println!("compaction_time_ms: {}", stats.compaction_time.as_millis());
+            println!("disk_size: {}", stats.disk_size);
+            println!("compaction_total_bytes: {}", stats.compaction_total_bytes);
+            println!("compaction_done_bytes: {}", stats.compaction_done_bytes);
+            println!("read_only: {}", stats.read_only);
+            println!("evictions: {}", stats.evictions);
+            for op in stats.ops {
+                println!(
+                    "{}: requests {}, errors {}, time_us {}",
+                    op.op,
+                    op.requests,
+                    op.errors,
+                    op.total_time.as_micros()
+                );
+            }
+        }
+        Command::Admin { command } => match command {
+            AdminCommand::Compact { addr } => {
+                let mut client = KvsClient::connect(addr).await?;
+                client.compact().await?;
+            }
+            AdminCommand::Flush { addr } => {
+                let mut client = KvsClient::connect(addr).await?;
+                client.flush().await?;
+            }
+            AdminCommand::RateLimit {
+                bytes_per_sec,
+                addr,
+            } => {
+                let mut client = KvsClient::connect(addr).await?;
+                client.set_compaction_rate_limit(bytes_per_sec).await?;
+            }
+        },
     }
     Ok(())
 }
+
+/// Returns the milliseconds since the Unix epoch at the given time.
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+}
@@ -8,15 +8,14 @@ use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
 use std::process::exit;
-use std::str::FromStr;
 
-use clap::{ArgEnum, Clap};
-use derive_more::Display;
+use clap::Clap;
 use log::LevelFilter;
+use smol::channel::{bounded, Receiver};
 
 use kvs::*;
 
-const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_ENGINE: &str = "kvs";
 
 #[derive(Clap, Debug)]
 #[clap(name = "kvs-server", version, author, about)]
@@ -31,33 +30,48 @@ struct Opt {
     addr: SocketAddr,
     #[clap(
         long,
-        about = "Sets the storage engine",
-        value_name = "ENGINE-NAME",
-        possible_values = &Engine::VARIANTS,
-        arg_enum,
-        parse(try_from_str)
+        about = "Sets the storage engine: kvs, sled, memory or lsm",
+        value_name = "ENGINE-NAME"
     )]
-    engine: Option<Engine>,
-}
-
-#[allow(non_camel_case_types)]
-#[derive(ArgEnum, Debug, Copy, Clone, PartialEq, Eq, Display)]
-enum Engine {
-    #[display(fmt = "kvs")]
-    kvs,
-    #[display(fmt = "sled")]
-    sled,
-}
-
-impl FromStr for Engine {
-    type Err = ();
-    fn from_str(s: &str) -> std::result::Result<Self, ()> {
-        match s {
-            "kvs" => Ok(Engine::kvs),
-            "sled" => Ok(Engine::sled),
-            _ => Err(()),
-        }
-    }
+    engine: Option<String>,
+    #[clap(
+        long,
+        about = "Skips corrupt records of the kvs engine instead of refusing to start"
+    )]
+    salvage: bool,
+    #[clap(
+        long,
+        about = "Runs the kvs engine as a cache holding at most the given live bytes",
+        value_name = "BYTES"
+    )]
+    cache_capacity: Option<u64>,
+    #[clap(
+        long,
+        about = "Saves the memory engine's data to a snapshot on shutdown and loads it on start"
+    )]
+    memory_snapshot: bool,
+    #[clap(long, about = "Records the requests of each operation, shown by stats")]
+    metrics: bool,
+    #[clap(long, about = "Logs every request with its duration")]
+    log_requests: bool,
+    #[clap(
+        long,
+        about = "Rejects writes of keys larger than the given bytes",
+        value_name = "BYTES"
+    )]
+    max_key_size: Option<usize>,
+    #[clap(
+        long,
+        about = "Rejects writes of values larger than the given bytes",
+        value_name = "BYTES"
+    )]
+    max_value_size: Option<usize>,
+    #[clap(
+        long,
+        about = "Caches read values in memory up to the given bytes",
+        value_name = "BYTES"
+    )]
+    read_cache: Option<u64>,
 }
 
 fn main() {
@@ -65,7 +79,7 @@ fn main() {
     let mut opt = Opt::parse();
     let res = current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
-            opt.engine = curr_engine;
+            opt.engine = curr_engine.clone();
         }
         if curr_engine.is_some() && opt.engine != curr_engine {
             error!("Wrong engine!");
@@ -81,46 +95,85 @@ fn main() {
 }
 
 async fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let registry = EngineRegistry::default();
+    let engine = opt.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+    if !registry.contains(engine) {
+        return Err(KvsError::UnknownEngine(engine.to_owned()));
+    }
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
-    let concurrency = num_cpus::get() as u32;
-    match engine {
-        Engine::kvs => {
-            run_with(
-                KvStore::<RayonThreadPool>::open(current_dir()?, concurrency)?,
-                opt.addr,
-            )
-            .await?
-        }
-        Engine::sled => {
-            run_with(
-                SledKvsEngine::<RayonThreadPool>::new(sled::open(current_dir()?)?, concurrency)?,
-                opt.addr,
-            )
-            .await?
-        }
-    }
+    fs::write(current_dir()?.join("engine"), engine)?;
+
+    let config = EngineConfig {
+        salvage: opt.salvage,
+        cache_capacity: opt.cache_capacity,
+        memory_snapshot: opt.memory_snapshot,
+        ..EngineConfig::new(current_dir()?, num_cpus::get() as u32)
+    };
+    run_layered(registry.open(engine, &config)?, &opt).await
+}
 
-    Ok(())
+/// Runs the server with the layers selected by the flags around the engine.
+async fn run_layered<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let limits = if opt.max_key_size.is_some() || opt.max_value_size.is_some() {
+        Some(LimitsLayer {
+            max_key_size: opt.max_key_size,
+            max_value_size: opt.max_value_size,
+        })
+    } else {
+        None
+    };
+    let engine = engine
+        .with_layer(opt.read_cache.map(CacheLayer::new))
+        .with_layer(limits)
+        .with_layer(if opt.log_requests {
+            Some(LoggingLayer::default())
+        } else {
+            None
+        })
+        .with_layer(if opt.metrics {
+            Some(MetricsLayer)
+        } else {
+            None
+        });
+
+    // Connections hold clones of the engine, so it is flushed explicitly to save the
+    // data of engines which keep it in memory, like the memory engine's snapshot.
+    let shutdown = shutdown_signal()?;
+    let server = run_with(engine.clone(), opt.addr);
+    smol::future::or(server, async {
+        let _ = shutdown.recv().await;
+        Ok(())
+    })
+    .await?;
+    info!("Shutting down");
+    KvsEngine::flush(&engine).await
 }
 
-fn current_engine() -> Result<Option<Engine>> {
+/// Returns a channel receiving a message once the server is asked to stop by SIGINT or
+/// SIGTERM.
+fn shutdown_signal() -> Result<Receiver<()>> {
+    let (tx, rx) = bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = tx.try_send(());
+    })
+    .map_err(|e| KvsError::StringError(e.to_string()))?;
+    Ok(rx)
+}
+
+fn current_engine() -> Result<Option<String>> {
     let engine = current_dir()?.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {:?}", e);
-            Ok(None)
-        }
+    let engine = fs::read_to_string(engine)?;
+    if engine.is_empty() {
+        warn!("The content of engine file is invalid: {:?}", engine);
+        return Ok(None);
     }
+    Ok(Some(engine))
 }
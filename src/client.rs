@@ -1,12 +1,12 @@
 use std::convert::TryInto;
 use std::net::{SocketAddr, TcpStream};
 
-use smol::io::{BufReader, BufWriter};
+use smol::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
 use smol::prelude::{AsyncReadExt, AsyncWriteExt};
 use smol::Async;
 
-use crate::common::{PacketSize, Request, Response};
-use crate::{KvsError, Result};
+use crate::common::{read_chunk, write_chunk, PacketSize, Request, Response, CHUNK_SIZE};
+use crate::{EngineStats, KvsError, Result, UserMeta, ValueMeta};
 
 /// Key value store client
 pub struct KvsClient {
@@ -46,6 +46,42 @@ impl KvsClient {
         }
     }
 
+    /// Get the value of a given key with its metadata from the server.
+    pub async fn get_with_meta(&mut self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        let b = serde_json::to_vec(&Request::GetWithMeta { key })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::GetWithMeta(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the values of the given keys from the server, in the order of the keys.
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let b = serde_json::to_vec(&Request::GetMany { keys })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::GetMany(values) => Ok(values),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
     /// Set the value of a string key in the server.
     pub async fn set(&mut self, key: String, value: String) -> Result<()> {
         let b = serde_json::to_vec(&Request::Set { key, value })?;
@@ -64,6 +100,96 @@ impl KvsClient {
         }
     }
 
+    /// Set the value of a string key in the server with user metadata attached.
+    pub async fn set_with_meta(
+        &mut self,
+        key: String,
+        value: String,
+        meta: UserMeta,
+    ) -> Result<()> {
+        let b = serde_json::to_vec(&Request::SetWithMeta { key, value, meta })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::SetWithMeta => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Set the value of a string key in the server to the contents of a reader.
+    ///
+    /// The value is sent in chunks as it is read, so it is never held in memory as a whole.
+    pub async fn set_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        key: String,
+        mut value: R,
+    ) -> Result<()> {
+        let b = serde_json::to_vec(&Request::SetStream { key })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let n = value.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            write_chunk(&mut self.writer, &chunk[..n]).await?;
+        }
+        write_chunk(&mut self.writer, &[]).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::SetStream => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the value of a given key from the server and write it to a writer.
+    ///
+    /// The value is written in chunks as it is received. Returns `false` if the key does
+    /// not exist. If the transfer is interrupted, part of the value may have been written
+    /// before the error is returned.
+    pub async fn get_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        key: String,
+        mut out: W,
+    ) -> Result<bool> {
+        let b = serde_json::to_vec(&Request::GetStream { key })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let resp: Response = serde_json::from_slice(&read_chunk(&mut self.reader).await?)?;
+        match resp {
+            Response::GetStream(true) => {}
+            Response::GetStream(false) => return Ok(false),
+            Response::Err(msg) => return Err(KvsError::StringError(msg)),
+            _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+        loop {
+            let chunk = read_chunk(&mut self.reader).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            out.write_all(&chunk).await?;
+        }
+        out.flush().await?;
+        Ok(true)
+    }
+
     /// Remove a string key in the server.
     pub async fn remove(&mut self, key: String) -> Result<()> {
         let b = serde_json::to_vec(&Request::Remove { key })?;
@@ -82,4 +208,130 @@ impl KvsClient {
             _ => Err(KvsError::StringError("Invalid response".to_owned())),
         }
     }
+
+    /// Atomically add `delta` to the integer value of a key in the server.
+    pub async fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let b = serde_json::to_vec(&Request::Incr { key, delta })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Incr(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Atomically subtract `delta` from the integer value of a key in the server.
+    pub async fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let b = serde_json::to_vec(&Request::Decr { key, delta })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Decr(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Atomically append a string to the value of a key in the server.
+    pub async fn append(&mut self, key: String, value: String) -> Result<String> {
+        let b = serde_json::to_vec(&Request::Append { key, value })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Append(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the statistics of the storage engine from the server.
+    pub async fn stats(&mut self) -> Result<EngineStats> {
+        let b = serde_json::to_vec(&Request::Stats)?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Compact the whole data in the server.
+    pub async fn compact(&mut self) -> Result<()> {
+        let b = serde_json::to_vec(&Request::Compact)?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Compact => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Make all the previous writes in the server durable.
+    pub async fn flush(&mut self) -> Result<()> {
+        let b = serde_json::to_vec(&Request::Flush)?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::Flush => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Limit the I/O rate of compactions in the server. A limit of 0 removes the limit.
+    pub async fn set_compaction_rate_limit(&mut self, bytes_per_sec: u64) -> Result<()> {
+        let b = serde_json::to_vec(&Request::SetCompactionRateLimit { bytes_per_sec })?;
+        let size = PacketSize::new(b.len().try_into()?);
+        self.writer.write(&size.to_bytes()).await?;
+        self.writer.write(&b).await?;
+        self.writer.flush().await?;
+
+        let mut contents = Vec::new();
+        self.reader.read_to_end(&mut contents).await?;
+        let resp: Response = serde_json::from_slice(&contents)?;
+        match resp {
+            Response::SetCompactionRateLimit => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
 }
@@ -1,19 +1,82 @@
 use std::convert::TryInto;
 
 use serde::{Deserialize, Serialize};
+use smol::io::{AsyncRead, AsyncWrite};
+use smol::prelude::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{EngineStats, KvsError, Result, UserMeta, ValueMeta};
+
+/// The maximum size of a chunk of a streamed value.
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    GetWithMeta {
+        key: String,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    SetWithMeta {
+        key: String,
+        value: String,
+        meta: UserMeta,
+    },
+    // followed by the chunks of the value
+    SetStream {
+        key: String,
+    },
+    GetStream {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    Decr {
+        key: String,
+        delta: i64,
+    },
+    Append {
+        key: String,
+        value: String,
+    },
+    Stats,
+    Compact,
+    Flush,
+    SetCompactionRateLimit {
+        bytes_per_sec: u64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Get(Option<String>),
+    GetWithMeta(Option<(String, ValueMeta)>),
+    GetMany(Vec<Option<String>>),
     Set,
+    SetWithMeta,
+    SetStream,
+    // followed by the chunks of the value if it exists
+    GetStream(bool),
     Remove,
+    Incr(i64),
+    Decr(i64),
+    Append(String),
+    Stats(EngineStats),
+    Compact,
+    Flush,
+    SetCompactionRateLimit,
     Err(String),
 }
 
@@ -38,3 +101,29 @@ impl PacketSize {
         self.0
     }
 }
+
+/// Writes a chunk of a streamed value prefixed with its size.
+///
+/// A value ends with an empty chunk.
+pub async fn write_chunk<W: AsyncWrite + Unpin>(writer: &mut W, chunk: &[u8]) -> Result<()> {
+    let size = PacketSize::new(chunk.len().try_into()?);
+    writer.write_all(&size.to_bytes()).await?;
+    writer.write_all(chunk).await?;
+    Ok(())
+}
+
+/// Reads a chunk written by `write_chunk`.
+pub async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut size = [0; 8];
+    reader.read_exact(&mut size).await?;
+    let n = PacketSize::from_bytes(&mut size.as_ref()).get_size();
+    if n > CHUNK_SIZE as u64 {
+        return Err(KvsError::StringError(format!(
+            "Chunk of {} bytes exceeds the maximum of {} bytes",
+            n, CHUNK_SIZE
+        )));
+    }
+    let mut chunk = vec![0; n.try_into()?];
+    reader.read_exact(&mut chunk).await?;
+    Ok(chunk)
+}
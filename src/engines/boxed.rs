@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::{EngineStats, KvsEngine, UserMeta, ValueMeta, ValueStream};
+use crate::Result;
+
+/// The object-safe counterpart of `KvsEngine`, implemented by every `KvsEngine`.
+///
+/// It is what a `BoxedEngine` holds behind a pointer, since `KvsEngine` requires
+/// `Clone` and cannot be made into a trait object.
+#[async_trait]
+pub trait DynEngine: Send + Sync + 'static {
+    /// See `KvsEngine::set`.
+    async fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// See `KvsEngine::get`.
+    async fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// See `KvsEngine::set_with_meta`.
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()>;
+
+    /// See `KvsEngine::get_with_meta`.
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>>;
+
+    /// See `KvsEngine::get_many`.
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+
+    /// See `KvsEngine::set_stream`.
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()>;
+
+    /// See `KvsEngine::get_stream`.
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>>;
+
+    /// See `KvsEngine::remove`.
+    async fn remove(&self, key: String) -> Result<()>;
+
+    /// See `KvsEngine::incr`.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// See `KvsEngine::decr`.
+    async fn decr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// See `KvsEngine::append`.
+    async fn append(&self, key: String, value: String) -> Result<String>;
+
+    /// See `KvsEngine::stats`.
+    async fn stats(&self) -> Result<EngineStats>;
+
+    /// See `KvsEngine::compact`.
+    async fn compact(&self) -> Result<()>;
+
+    /// See `KvsEngine::flush`.
+    async fn flush(&self) -> Result<()>;
+
+    /// See `KvsEngine::set_compaction_rate_limit`.
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()>;
+}
+
+#[async_trait]
+impl<E: KvsEngine> DynEngine for E {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key).await
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        KvsEngine::set_with_meta(self, key, value, meta).await
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        KvsEngine::get_with_meta(self, key).await
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvsEngine::get_many(self, keys).await
+    }
+
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        KvsEngine::set_stream(self, key, chunks).await
+    }
+
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        KvsEngine::get_stream(self, key).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key).await
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        KvsEngine::incr(self, key, delta).await
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        KvsEngine::decr(self, key, delta).await
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        KvsEngine::append(self, key, value).await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        KvsEngine::stats(self).await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        KvsEngine::compact(self).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        KvsEngine::flush(self).await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        KvsEngine::set_compaction_rate_limit(self, bytes_per_sec).await
+    }
+}
+
+/// A `KvsEngine` wrapping any engine behind a trait object, so that engines of
+/// different types can be handled as the same type.
+///
+/// ```rust
+/// # use kvs::{BoxedEngine, KvsEngine, MemKvsEngine, MetricsLayer};
+/// let engines = vec![
+///     BoxedEngine::new(MemKvsEngine::new()),
+///     BoxedEngine::new(MemKvsEngine::new().with_layer(MetricsLayer)),
+/// ];
+/// ```
+///
+/// Clones share the wrapped engine.
+#[derive(Clone)]
+pub struct BoxedEngine {
+    inner: Arc<dyn DynEngine>,
+}
+
+impl BoxedEngine {
+    /// Wraps an engine.
+    pub fn new<E: KvsEngine>(engine: E) -> Self {
+        BoxedEngine {
+            inner: Arc::new(engine),
+        }
+    }
+}
+
+#[async_trait]
+impl KvsEngine for BoxedEngine {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key).await
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        self.inner.set_with_meta(key, value, meta).await
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        self.inner.get_with_meta(key).await
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        self.inner.set_stream(key, chunks).await
+    }
+
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        self.inner.get_stream(key).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key).await
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.inner.incr(key, delta).await
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.inner.decr(key, delta).await
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        self.inner.append(key, value).await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        self.inner.stats().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        self.inner.set_compaction_rate_limit(bytes_per_sec).await
+    }
+}
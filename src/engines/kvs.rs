@@ -1,30 +1,141 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use crossbeam::queue::ArrayQueue;
-use crossbeam_skiplist::SkipMap;
+use crossbeam_skiplist::{SkipMap, SkipSet};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use smol::channel::bounded;
+use smol::channel::{bounded, Sender};
+use smol::Timer;
 
-use super::KvsEngine;
-use crate::{KvsError, Result, ThreadPool};
+use self::history::{now_millis, system_time, Version};
+use self::manifest::Manifest;
+use self::mmap::MmapCache;
+use self::rate_limiter::{RateLimited, RateLimiter};
+use self::reader_pool::ReaderPool;
+use self::salvage::salvage_gen;
+use self::snapshot::IndexSnapshot;
+use self::stream::{read_value, stage_path, stage_value, write_staged_record};
+use super::{EngineStats, KvsEngine, UserMeta, ValueMeta, ValueStream};
+use crate::{KvsError, OsVfs, Result, ThreadPool, Vfs, VfsFile};
+
+mod history;
+mod manifest;
+mod mmap;
+mod rate_limiter;
+mod reader_pool;
+mod salvage;
+mod snapshot;
+mod stream;
+
+pub use self::history::{HistoryRetention, KeyVersion};
+pub use self::salvage::SalvageReport;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_COMPACTION_GARBAGE_RATIO: f64 = 0.5;
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_READERS: u32 = 32;
+// the number of chunks of a streamed value read ahead of the receiver
+const STREAM_BUFFER: usize = 4;
+// how long a streamed read waits for the receiver to take a chunk before it gives up
+const STREAM_SEND_TIMEOUT: Duration = Duration::from_secs(60);
+// the fraction of the cache capacity which eviction brings the live bytes down to
+const EVICTION_TARGET_RATIO: f64 = 0.9;
+
+/// Options to configure a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// The size in bytes after which the active log is sealed and a new generation is
+    /// started. Compaction output is split into generations of this size as well.
+    ///
+    /// A generation may exceed it by the size of its last record.
+    pub max_segment_size: u64,
+
+    /// The ratio of stale bytes in a generation above which the generation is rewritten
+    /// by a compaction. Generations below it are left untouched.
+    pub compaction_garbage_ratio: f64,
+
+    /// The maximum number of bytes per second read and written by compactions,
+    /// or 0 for no limit. It can be changed later with `set_compaction_rate_limit`.
+    pub compaction_rate_limit: u64,
+
+    /// The number of bytes written to the log after which the index is persisted to
+    /// a snapshot, or 0 to disable snapshots. `KvStore::open` loads the latest snapshot
+    /// and only replays the log written after it.
+    pub snapshot_interval: u64,
+
+    /// The maximum number of readers serving concurrent reads. A reader keeps a file
+    /// handle per generation, so it bounds the number of open files. Reads wait for
+    /// a free reader beyond it.
+    pub max_readers: u32,
+
+    /// Whether reads from generations which are no longer written to go through memory
+    /// maps shared by all the readers instead of a file handle of each reader.
+    pub mmap_reads: bool,
+
+    /// Whether every write is synced to disk before it is acknowledged. Otherwise
+    /// a write survives a crash only after the next `flush`, roll or compaction.
+    pub sync_writes: bool,
+
+    /// Whether `KvStore::open` skips corrupt records instead of failing. A damaged
+    /// generation is rewritten with its intact records, and what was lost is reported
+    /// by `KvStore::salvage_report`. The index snapshot is not used in this mode, so
+    /// the whole log is replayed.
+    pub salvage: bool,
+
+    /// How many versions of each key are retained for `KvStore::history` and
+    /// `KvStore::get_at_version`. Compactions keep the retained versions. Only
+    /// the current value is retained by default.
+    pub history_retention: HistoryRetention,
+
+    /// The maximum number of live bytes in cache mode, or 0 to disable cache mode.
+    ///
+    /// Once a write takes the live bytes above it, the least recently read or written
+    /// keys are evicted until the live bytes are back to 90% of it. An eviction writes
+    /// a tombstone which compactions reclaim like a removal. Keys loaded from disk count
+    /// as the least recently used until they are accessed.
+    pub cache_capacity: u64,
+
+    /// The filesystem holding the data, which is the one of the operating system
+    /// by default.
+    pub vfs: Arc<dyn Vfs>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            compaction_garbage_ratio: DEFAULT_COMPACTION_GARBAGE_RATIO,
+            compaction_rate_limit: 0,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            max_readers: DEFAULT_MAX_READERS,
+            mmap_reads: false,
+            sync_writes: false,
+            salvage: false,
+            history_retention: HistoryRetention::default(),
+            cache_capacity: 0,
+            vfs: Arc::new(OsVfs),
+        }
+    }
+}
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
+/// The live generations are recorded in a `MANIFEST` file.
 /// A skip list in memory stores the keys and the value locations for fast query.
+/// It is periodically persisted to an `INDEX` snapshot to speed up restarts.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result,ThreadPool, RayonThreadPool};
@@ -43,10 +154,22 @@ pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data.
     path: Arc<PathBuf>,
     // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<SkipMap<String, IndexEntry>>,
+    // the clock ordering the accesses for cache eviction, which reads record in cache mode
+    access_clock: Option<Arc<AtomicU64>>,
+    // retained versions of each key, oldest first, if older versions are retained
+    history: Arc<SkipMap<String, Vec<Version>>>,
     writer: Arc<Mutex<KvStoreWriter>>,
+    // statistics published by the writer
+    stats: Arc<Mutex<EngineStats>>,
+    rate_limiter: Arc<RateLimiter>,
     thread_pool: P,
-    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    reader_pool: Arc<ReaderPool>,
+    vfs: Arc<dyn Vfs>,
+    // the number of the next staging file of a streamed value
+    next_stage: Arc<AtomicU64>,
+    // what the open skipped in salvage mode
+    salvage_report: Option<Arc<SalvageReport>>,
 }
 
 #[async_trait]
@@ -62,7 +185,9 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         let writer = self.writer.clone();
         let (tx, rx) = bounded(100);
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
+            let res = write(&writer, |writer| {
+                writer.set(key, value, UserMeta::default())
+            });
 
             smol::block_on(async {
                 if !tx.is_closed() && tx.send(res).await.is_err() {
@@ -78,22 +203,18 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// Returns `None` if the given key does not exist.
     async fn get(&self, key: String) -> Result<Option<String>> {
-        let reader_pool = self.reader_pool.clone();
+        let reader = self.reader_pool.acquire().await?;
         let index = self.index.clone();
+        let access_clock = self.access_clock.clone();
         let (tx, rx) = bounded(1);
         self.thread_pool.spawn(move || {
             let res = (move || {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
+                if let Some(cmd_pos) = lookup(&index, &key, access_clock.as_deref()) {
+                    if let Command::Set { value, .. } = reader.read_command(cmd_pos)? {
                         Ok(Some(value))
                     } else {
                         Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
+                    }
                 } else {
                     Ok(None)
                 }
@@ -109,6 +230,169 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         rx.recv().await?
     }
 
+    /// Sets the value of a string key to a string with user metadata attached.
+    ///
+    /// The metadata is stored in the log record of the value.
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = write(&writer, |writer| writer.set(key, value, meta));
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Gets the string value of a given string key with its metadata.
+    ///
+    /// Returns `None` if the given key does not exist.
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        let reader = self.reader_pool.acquire().await?;
+        let index = self.index.clone();
+        let access_clock = self.access_clock.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = match lookup(&index, &key, access_clock.as_deref()) {
+                Some(cmd_pos) => reader.read_value_meta(cmd_pos).map(Some),
+                None => Ok(None),
+            };
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Gets the string values of the given string keys.
+    ///
+    /// The values are read in a single job in the order of their positions in the log,
+    /// so reads of a generation are sequential.
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let reader = self.reader_pool.acquire().await?;
+        let index = self.index.clone();
+        let access_clock = self.access_clock.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = (move || {
+                let mut cmd_positions: Vec<(usize, CommandPos)> = keys
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, key)| {
+                        lookup(&index, key, access_clock.as_deref()).map(|cmd_pos| (i, cmd_pos))
+                    })
+                    .collect();
+                cmd_positions.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+
+                let mut values = vec![None; keys.len()];
+                for (i, cmd_pos) in cmd_positions {
+                    if let Command::Set { value, .. } = reader.read_command(cmd_pos)? {
+                        values[i] = Some(value);
+                    } else {
+                        return Err(KvsError::UnexpectedCommandType);
+                    }
+                }
+                Ok(values)
+            })();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Sets the value of a string key to the chunks of a stream.
+    ///
+    /// The value is written to a staging file as it is received and then copied to
+    /// the log, so other writes only wait for the copy. Staging waits for the sender,
+    /// so it runs on a blocking thread rather than on the thread pool.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    ///
+    /// It propagates errors received from the stream and I/O errors during staging the
+    /// value or writing the log.
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        let writer = self.writer.clone();
+        let vfs = Arc::clone(&self.vfs);
+        let stage_path = stage_path(&self.path, self.next_stage.fetch_add(1, Ordering::Relaxed));
+        let staged = smol::unblock({
+            let vfs = Arc::clone(&vfs);
+            let stage_path = stage_path.clone();
+            move || stage_value(&*vfs, &stage_path, chunks)
+        })
+        .await;
+
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = staged.and_then(|()| {
+                let mut staged = BufReader::new(vfs.open(&stage_path)?);
+                write(&writer, |writer| writer.set_staged(key, &mut staged))
+            });
+            match vfs.remove_file(&stage_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("{:?} cannot be deleted: {}", stage_path, e);
+                }
+                _ => {}
+            }
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Gets the string value of a given string key as a stream of chunks.
+    ///
+    /// The value is read from the log as the chunks are received, holding a reader
+    /// until the end of the value. Each chunk is valid UTF-8.
+    ///
+    /// The read waits for the receiver, so it runs on a blocking thread rather than on
+    /// the thread pool. It fails if a chunk is not received within a minute, which
+    /// frees the reader of a stalled receiver.
+    ///
+    /// Returns `None` if the given key does not exist.
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        let cmd_pos = match lookup(&self.index, &key, self.access_clock.as_deref()) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        let reader = self.reader_pool.acquire().await?;
+        let (tx, rx) = bounded(STREAM_BUFFER);
+        smol::unblock(move || {
+            let res = reader.read_and(cmd_pos, |record| {
+                read_value(record, &key, |chunk| send_chunk(&tx, chunk))
+            });
+
+            if let Err(e) = res {
+                if !tx.is_closed() && tx.try_send(Err(e)).is_err() {
+                    error!("Receiving end is dropped or stalled");
+                }
+            }
+        })
+        .detach();
+
+        Ok(Some(rx))
+    }
+
     /// Removes a given key.
     ///
     /// # Error
@@ -120,7 +404,51 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         let writer = self.writer.clone();
         let (tx, rx) = bounded(1);
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
+            let res = write(&writer, |writer| writer.remove(key));
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Atomically adds `delta` to the integer value of a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the existing value is not an integer.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = write(&writer, |writer| writer.incr(key, delta));
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Atomically appends a string to the value of a given key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = write(&writer, |writer| writer.append(key, value));
 
             smol::block_on(async {
                 if !tx.is_closed() && tx.send(res).await.is_err() {
@@ -131,10 +459,90 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
 
         rx.recv().await?
     }
+
+    /// Returns statistics about the keys and the log files.
+    ///
+    /// It does not wait for a running compaction, whose progress is reported instead.
+    async fn stats(&self) -> Result<EngineStats> {
+        let index = self.index.clone();
+        let stats = self.stats.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let mut res = stats.lock().unwrap().clone();
+            res.keys = index.len() as u64;
+            res.live_bytes = index.iter().map(|entry| entry.value().cmd_pos.len).sum();
+            let res = Ok(res);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Rewrites all the generations regardless of their garbage ratio.
+    ///
+    /// Other writes go on while the records are copied. It waits for a running
+    /// compaction first.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the compaction.
+    async fn compact(&self) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = run_compaction(&writer, true, |writer| writer.manifest.gens.clone());
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Flushes the active log and syncs it to disk.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the sync.
+    async fn flush(&self) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            // A failed sync does not corrupt the log, so only the write of the buffer
+            // turns the store read-only.
+            let res = writer
+                .write_op(KvStoreWriter::flush_buffer)
+                .and_then(|()| writer.sync());
+            drop(writer);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Limits the I/O rate of compactions. It takes effect on a running compaction too.
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        self.rate_limiter.set_rate(bytes_per_sec);
+        Ok(())
+    }
 }
 
 impl<P: ThreadPool> KvStore<P> {
-    /// Opens a `KvStore` with the given path.
+    /// Opens a `KvStore` with the given path and the default options.
     ///
     /// This will create a new directory if the given one does not exist.
     ///
@@ -142,238 +550,1294 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_with(path, concurrency, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// See `open` for details.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
         let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        let vfs = Arc::clone(&options.vfs);
+        vfs.create_dir_all(&path)?;
 
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
+        let history = Arc::new(SkipMap::new());
+        let keeps_history = options.history_retention.keeps_history();
+
+        // A directory without a manifest is either empty or written by an older version,
+        // in which case every log file in it is live.
+        let disk_gen_list = sorted_gen_list(&*vfs, &path)?;
+        let mut manifest = match Manifest::load(&*vfs, &path)? {
+            Some(manifest) => manifest,
+            None => Manifest {
+                gens: disk_gen_list.clone(),
+                compaction_gen: 0,
+            },
+        };
+        remove_orphan_files(&*vfs, &path, &disk_gen_list, &manifest.gens)?;
 
-        let gen_list = sorted_gen_list(&path)?;
+        // The snapshot covers the generations before its active one, so only the log
+        // written after it is replayed.
         let mut uncompacted = 0;
+        let mut next_version = 1;
+        let mut snapshot_end = None;
+        let snapshot = if options.salvage {
+            None
+        } else {
+            IndexSnapshot::load(&*vfs, &path)?
+        };
+        if let Some(snapshot) = snapshot {
+            // A snapshot without the history cannot be used if the history is retained,
+            // and one without the creation times cannot be used at all.
+            if snapshot.is_valid(&*vfs, &path, &manifest)?
+                && snapshot.history.is_some() == keeps_history
+                && matches!(&snapshot.created, Some(created) if created.len() == snapshot.entries.len())
+            {
+                let created = snapshot.created.unwrap_or_default();
+                for ((key, cmd_pos), created) in snapshot.entries.into_iter().zip(created) {
+                    index.insert(key, IndexEntry::new(cmd_pos, created, 0));
+                }
+                for (key, versions) in snapshot.history.unwrap_or_default() {
+                    history.insert(key, versions);
+                }
+                uncompacted = snapshot.uncompacted;
+                next_version = snapshot.next_version;
+                snapshot_end = Some((snapshot.gen, snapshot.pos));
+            }
+        }
+        let replay_from = |gen| match snapshot_end {
+            Some((end_gen, _)) if gen < end_gen => None,
+            Some((end_gen, end_pos)) if gen == end_gen => Some(end_pos),
+            _ => Some(0),
+        };
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+        // Generations are parsed in parallel and then merged into the index in order,
+        // so that the newest command of a key wins.
+        let active_gen = manifest.gens.last().copied();
+        let loaded = manifest
+            .gens
+            .par_iter()
+            .map(|&gen| -> Result<_> {
+                let mut reader = BufReaderWithPos::new(vfs.open(&log_path(&path, gen))?)?;
+                let start = match replay_from(gen) {
+                    Some(start) => start,
+                    None => return Ok((gen, reader, None, None)),
+                };
+                // Only the active log may end with a record cut short by a crash.
+                let active = Some(gen) == active_gen;
+                match load(gen, start, &mut reader, keeps_history, active) {
+                    Err(e) if options.salvage && is_corruption(&e) => {
+                        warn!("Salvaging generation {} after a corrupt record: {}", gen, e);
+                        // The rewrite moves records, so the snapshot must not be used again.
+                        IndexSnapshot::remove(&*vfs, &path)?;
+                        let mut salvaged = salvage_gen(&*vfs, &path, gen)?;
+                        let mut reader = BufReaderWithPos::new(vfs.open(&log_path(&path, gen))?)?;
+                        let mut gen_index = load(gen, 0, &mut reader, keeps_history, false)?;
+                        gen_index.lost_keys = mem::take(&mut salvaged.lost_keys);
+                        Ok((gen, reader, Some(gen_index), Some(salvaged)))
+                    }
+                    res => {
+                        let gen_index = res?;
+                        if let Some(end) = gen_index.torn {
+                            warn!("Truncating a torn record at {} of generation {}", end, gen);
+                            let file = vfs.append(&log_path(&path, gen))?;
+                            file.set_len(end)?;
+                            file.sync_all()?;
+                        }
+                        Ok((gen, reader, Some(gen_index), None))
+                    }
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut gen_stats = BTreeMap::new();
+        let mut salvage_report = SalvageReport::default();
+        let mut lost_keys = BTreeSet::new();
+        // the version of each indexed "set" command, which is unknown for the entries
+        // of the snapshot because they are older than the replayed ones
+        let mut index_versions = HashMap::new();
+        for (gen, reader, gen_index, salvaged) in loaded {
+            if let Some(gen_index) = gen_index {
+                next_version = next_version.max(gen_index.max_version + 1);
+                uncompacted +=
+                    gen_index.merge_into(&index, &mut index_versions, &history, &mut lost_keys);
+            }
+            if let Some(salvaged) = salvaged {
+                salvage_report.damaged_gens.push(gen);
+                salvage_report.lost_records += salvaged.lost_records;
+                salvage_report.lost_bytes += salvaged.lost_bytes;
+            }
+            let size = vfs.file_size(&log_path(&path, gen))?;
+            gen_stats.insert(gen, GenStats { size, live: 0 });
             readers.insert(gen, reader);
         }
+        // The retention may have changed since the versions were written.
+        let now = now_millis();
+        for entry in history.iter() {
+            let mut versions = entry.value().clone();
+            if !options
+                .history_retention
+                .prune(&mut versions, now)
+                .is_empty()
+            {
+                history.insert(entry.key().clone(), versions);
+            }
+        }
+        let older_versions = history.iter().flat_map(|entry| {
+            let versions = entry.value();
+            versions[..versions.len() - 1]
+                .iter()
+                .map(|version| version.cmd_pos)
+                .collect::<Vec<_>>()
+        });
+        for cmd_pos in index
+            .iter()
+            .map(|entry| entry.value().cmd_pos)
+            .chain(older_versions)
+        {
+            if let Some(stats) = gen_stats.get_mut(&cmd_pos.gen) {
+                stats.live += cmd_pos.len;
+            }
+        }
+
+        salvage_report.lost_keys = lost_keys.into_iter().collect();
+        if !salvage_report.damaged_gens.is_empty() {
+            warn!(
+                "Salvaged generations {:?}: lost {} records ({} bytes) and {} keys",
+                salvage_report.damaged_gens,
+                salvage_report.lost_records,
+                salvage_report.lost_bytes,
+                salvage_report.lost_keys.len()
+            );
+        }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
-        let safe_point = Arc::new(AtomicU64::new(0));
+        // Orphan files which failed to be deleted must not be reused as the active log.
+        let last_gen = manifest.gens.iter().chain(&disk_gen_list).max();
+        let current_gen = last_gen.unwrap_or(&0) + 1;
+        let writer = new_log_file(&*vfs, &path, current_gen)?;
+        manifest.gens.push(current_gen);
+        manifest.store(&*vfs, &path)?;
+        gen_stats.insert(current_gen, GenStats::default());
+        let live_gens = Arc::new(manifest.gens.iter().copied().collect());
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            safe_point,
+            vfs: Arc::clone(&vfs),
+            live_gens,
             readers: RefCell::new(readers),
+            mmaps: if options.mmap_reads {
+                Some(Arc::new(MmapCache::default()))
+            } else {
+                None
+            },
         };
 
+        let max_readers = options.max_readers as usize;
+        let access_clock = Arc::new(AtomicU64::new(0));
+        let cache_mode = options.cache_capacity > 0;
+        let salvage_report = if options.salvage {
+            Some(Arc::new(salvage_report))
+        } else {
+            None
+        };
+        let stats = Arc::new(Mutex::new(EngineStats::default()));
+        let rate_limiter = Arc::new(RateLimiter::new(options.compaction_rate_limit));
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
             current_gen,
             uncompacted,
             path: Arc::clone(&path),
+            vfs: Arc::clone(&vfs),
             index: Arc::clone(&index),
+            history: Arc::clone(&history),
+            next_version,
+            manifest,
+            gen_stats,
+            unsnapshotted: 0,
+            read_only: None,
+            options,
+            stats: Arc::clone(&stats),
+            rate_limiter: Arc::clone(&rate_limiter),
+            compaction_lock: Arc::new(Mutex::new(())),
+            access_clock: Arc::clone(&access_clock),
+        };
+        writer.publish_stats();
+
+        let thread_pool = P::new(concurrency)?;
+        let reader_pool = Arc::new(ReaderPool::new(reader, max_readers));
+
+        Ok(KvStore {
+            path,
+            index,
+            access_clock: if cache_mode { Some(access_clock) } else { None },
+            history,
+            writer: Arc::new(Mutex::new(writer)),
+            stats,
+            rate_limiter,
+            thread_pool,
+            reader_pool,
+            vfs,
+            next_stage: Arc::new(AtomicU64::new(0)),
+            salvage_report,
+        })
+    }
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// Gets the value of a given key in the given version.
+    ///
+    /// Returns `None` if the version of the key is not retained. See `history` for the
+    /// retained versions.
+    pub async fn get_at_version(&self, key: String, version: u64) -> Result<Option<String>> {
+        let reader = self.reader_pool.acquire().await?;
+        let index = self.index.clone();
+        let history = self.history.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = (move || {
+                if let Some(versions) = history.get(&key) {
+                    match versions.value().iter().find(|v| v.version == version) {
+                        Some(v) => Ok(Some(reader.read_version(v.cmd_pos)?.value)),
+                        None => Ok(None),
+                    }
+                } else if let Some(entry) = index.get(&key) {
+                    // Only the current version is retained.
+                    let current = reader.read_version(entry.value().cmd_pos)?;
+                    if current.version == version {
+                        Ok(Some(current.value))
+                    } else {
+                        Ok(None)
+                    }
+                } else {
+                    Ok(None)
+                }
+            })();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Returns the retained versions of a given key, oldest first.
+    ///
+    /// The last one is the current value. Returns an empty list if the key does not exist.
+    pub async fn history(&self, key: String) -> Result<Vec<KeyVersion>> {
+        let reader = self.reader_pool.acquire().await?;
+        let index = self.index.clone();
+        let history = self.history.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let cmd_positions: Vec<CommandPos> = match history.get(&key) {
+                Some(versions) => versions.value().iter().map(|v| v.cmd_pos).collect(),
+                None => index
+                    .get(&key)
+                    .map(|entry| entry.value().cmd_pos)
+                    .into_iter()
+                    .collect(),
+            };
+            let res = cmd_positions
+                .into_iter()
+                .map(|cmd_pos| reader.read_version(cmd_pos))
+                .collect::<Result<Vec<_>>>();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Returns what the open skipped if the store was opened in salvage mode.
+    pub fn salvage_report(&self) -> Option<&SalvageReport> {
+        self.salvage_report.as_deref()
+    }
+
+    /// Leaves the read-only mode entered after a write failure, e.g. once disk space
+    /// is freed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while discarding the partial record of the failed write.
+    /// The store stays read-only in that case.
+    pub async fn recover(&self) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().recover();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+}
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    // generations listed in the manifest
+    live_gens: Arc<SkipSet<u64>>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<Box<dyn VfsFile>>>>,
+    // shared by all the readers if `mmap_reads` is enabled
+    mmaps: Option<Arc<MmapCache>>,
+}
+
+impl KvStoreReader {
+    /// Close file handles of generations which are no longer live.
+    ///
+    /// A generation is removed from `live_gens` after a compaction rewrites it. The
+    /// in-memory index contains no entries of such a generation by then, so we can
+    /// safely close those file handles and the stale files can be deleted.
+    fn close_stale_handles(&self) {
+        let live_gens = &self.live_gens;
+        self.readers
+            .borrow_mut()
+            .retain(|gen, _| live_gens.contains(gen));
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        // Only the active generation, which is the newest one, is still written to.
+        if let Some(mmaps) = &self.mmaps {
+            if matches!(self.live_gens.back(), Some(active) if *active.value() > cmd_pos.gen) {
+                let mmap = mmaps.get(&*self.vfs, &self.path, cmd_pos.gen)?;
+                let range = cmd_pos.pos as usize..(cmd_pos.pos + cmd_pos.len) as usize;
+                let mut cmd_reader = mmap
+                    .as_ref()
+                    .as_ref()
+                    .get(range)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                return f(&mut cmd_reader);
+            }
+        }
+
+        let mut readers = self.readers.borrow_mut();
+
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        // We don't use entry API here because we want the errors to be propogated.
+        if !readers.contains_key(&cmd_pos.gen) {
+            let reader = BufReaderWithPos::new(self.vfs.open(&log_path(&self.path, cmd_pos.gen))?)?;
+            readers.insert(cmd_pos.gen, reader);
+        }
+
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+
+        let mut cmd_reader = reader.take(cmd_pos.len);
+        f(&mut cmd_reader)
+    }
+
+    /// Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |cmd_reader| {
+            Ok(serde_json::from_reader(cmd_reader)?)
+        })
+    }
+
+    /// Read the "set" command at the given `CommandPos` with the metadata of its value.
+    fn read_value_meta(&self, cmd_pos: CommandPos) -> Result<(String, ValueMeta)> {
+        match self.read_command(cmd_pos)? {
+            Command::Set {
+                value,
+                time,
+                created,
+                flags,
+                content_type,
+                ..
+            } => {
+                let meta = ValueMeta {
+                    created: system_time(created_time(created, time)),
+                    updated: system_time(time),
+                    user: UserMeta {
+                        flags,
+                        content_type,
+                    },
+                };
+                Ok((value, meta))
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Read the user metadata of the "set" command at the given `CommandPos`, skipping
+    /// its value, which may be large.
+    fn read_user_meta(&self, cmd_pos: CommandPos) -> Result<UserMeta> {
+        #[derive(Deserialize)]
+        enum MetaCommand {
+            Set {
+                #[serde(default)]
+                flags: u32,
+                #[serde(default)]
+                content_type: Option<String>,
+            },
+            Remove {},
+        }
+
+        self.read_and(cmd_pos, |cmd_reader| {
+            match serde_json::from_reader(cmd_reader)? {
+                MetaCommand::Set {
+                    flags,
+                    content_type,
+                } => Ok(UserMeta {
+                    flags,
+                    content_type,
+                }),
+                MetaCommand::Remove {} => Err(KvsError::UnexpectedCommandType),
+            }
+        })
+    }
+
+    /// Read the "set" command at the given `CommandPos` as a version of its key.
+    fn read_version(&self, cmd_pos: CommandPos) -> Result<KeyVersion> {
+        match self.read_command(cmd_pos)? {
+            Command::Set {
+                value,
+                version,
+                time,
+                ..
+            } => Ok(KeyVersion {
+                version,
+                time: system_time(time),
+                value,
+            }),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            vfs: Arc::clone(&self.vfs),
+            live_gens: Arc::clone(&self.live_gens),
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+            mmaps: self.mmaps.clone(),
+        }
+    }
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithPos<Box<dyn VfsFile>>,
+    current_gen: u64,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
+    uncompacted: u64,
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    index: Arc<SkipMap<String, IndexEntry>>,
+    history: Arc<SkipMap<String, Vec<Version>>>,
+    // the version number of the next "set" command
+    next_version: u64,
+    manifest: Manifest,
+    // byte accounting of each live generation
+    gen_stats: BTreeMap<u64, GenStats>,
+    // the number of bytes written after the last index snapshot
+    unsnapshotted: u64,
+    // the failure which made the store read-only, if any
+    read_only: Option<String>,
+    options: KvStoreOptions,
+    stats: Arc<Mutex<EngineStats>>,
+    rate_limiter: Arc<RateLimiter>,
+    // held while a compaction runs, so that only one runs at a time
+    compaction_lock: Arc<Mutex<()>>,
+    // the clock ordering the accesses for cache eviction
+    access_clock: Arc<AtomicU64>,
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let (version, time) = (self.next_version, now_millis());
+        let created = self.created(&key, time);
+        let cmd = Command::set(key, value, version, time, created, meta);
+        let pos = self.writer.pos;
+        serde_json::to_writer(&mut self.writer, &cmd)?;
+        if let Command::Set { key, .. } = cmd {
+            self.commit_set(key, pos, version, time, created)?;
+        }
+
+        self.maybe_roll_and_snapshot()
+    }
+
+    /// Sets a key to a value staged by `stage_value`, keeping its user metadata.
+    fn set_staged(&mut self, key: String, staged: &mut impl Read) -> Result<()> {
+        let (version, time) = (self.next_version, now_millis());
+        let created = self.created(&key, time);
+        let meta = match self.index.get(&key) {
+            Some(entry) => self.reader.read_user_meta(entry.value().cmd_pos)?,
+            None => UserMeta::default(),
         };
+        let pos = self.writer.pos;
+        write_staged_record(
+            &mut self.writer,
+            &key,
+            staged,
+            version,
+            time,
+            created,
+            &meta,
+        )?;
+        self.commit_set(key, pos, version, time, created)?;
+
+        self.maybe_roll_and_snapshot()
+    }
+
+    /// Flushes a "set" record written at `pos` of the active log and points the key to it.
+    fn commit_set(
+        &mut self,
+        key: String,
+        pos: u64,
+        version: u64,
+        time: u64,
+        created: u64,
+    ) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.sync_writes {
+            self.writer.get_ref().sync_all()?;
+        }
+        self.next_version += 1;
+
+        let cmd_pos = (self.current_gen, pos..self.writer.pos).into();
+        self.mark_written(cmd_pos, true);
+        let old_cmd = self.index.get(&key).map(|entry| entry.value().cmd_pos);
+        let access = tick(&self.access_clock);
+        self.index
+            .insert(key.clone(), IndexEntry::new(cmd_pos, created, access));
+        if self.options.history_retention.keeps_history() {
+            // The previous version stays live until the retention drops it.
+            let mut versions = self
+                .history
+                .get(&key)
+                .map(|entry| entry.value().clone())
+                .unwrap_or_default();
+            versions.push(Version {
+                version,
+                time,
+                cmd_pos,
+            });
+            self.retain_versions(key, versions, time);
+        } else if let Some(old_cmd) = old_cmd {
+            self.mark_stale(old_cmd);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.index.contains_key(&key) {
+            self.write_remove(key)?;
+            self.maybe_roll_and_snapshot()
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Writes a "remove" record of an existing key and drops it from the index.
+    fn write_remove(&mut self, key: String) -> Result<()> {
+        let cmd = Command::remove(key);
+        let pos = self.writer.pos;
+        serde_json::to_writer(&mut self.writer, &cmd)?;
+        self.writer.flush()?;
+        if self.options.sync_writes {
+            self.writer.get_ref().sync_all()?;
+        }
+
+        if let Command::Remove { key } = cmd {
+            let old_cmd = self
+                .index
+                .remove(&key)
+                .expect("key not found")
+                .value()
+                .cmd_pos;
+            self.mark_stale(old_cmd);
+            let history = Arc::clone(&self.history);
+            if let Some(entry) = history.remove(&key) {
+                let versions = entry.value();
+                for version in &versions[..versions.len() - 1] {
+                    self.mark_stale(version.cmd_pos);
+                }
+            }
+            // the "remove" command itself can be deleted in a compaction
+            // so it is not counted as live
+            self.mark_written((self.current_gen, pos..self.writer.pos).into(), false);
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used keys in cache mode if the live bytes exceed
+    /// the cache capacity.
+    fn maybe_evict(&mut self) -> Result<()> {
+        let capacity = self.options.cache_capacity;
+        if capacity == 0 || self.live_bytes() <= capacity {
+            return Ok(());
+        }
+
+        // Evicting down to a fraction of the capacity spreads the cost of collecting
+        // the keys over the following writes.
+        let target = (capacity as f64 * EVICTION_TARGET_RATIO) as u64;
+        let mut keys: Vec<(u64, String)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.value().last_access(), entry.key().clone()))
+            .collect();
+        keys.sort_unstable();
+        let mut evictions = 0;
+        for (_, key) in keys {
+            if self.live_bytes() <= target {
+                break;
+            }
+            self.write_remove(key)?;
+            evictions += 1;
+        }
+        info!("Evicted {} keys", evictions);
+        self.stats.lock().unwrap().evictions += evictions;
+        Ok(())
+    }
+
+    /// Returns the number of bytes of the records which are still needed.
+    fn live_bytes(&self) -> u64 {
+        self.gen_stats.values().map(|stats| stats.live).sum()
+    }
+
+    /// The user metadata of the key is kept.
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let (value, meta) = match self.get(&key)? {
+            Some((value, meta)) => (
+                value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+                meta.user,
+            ),
+            None => (0, UserMeta::default()),
+        };
+        let value = value.checked_add(delta).ok_or(KvsError::IntegerOverflow)?;
+        self.set(key, value.to_string(), meta)?;
+        Ok(value)
+    }
+
+    /// The user metadata of the key is kept.
+    fn append(&mut self, key: String, value: String) -> Result<String> {
+        let (value, meta) = match self.get(&key)? {
+            Some((old_value, meta)) => (old_value + &value, meta.user),
+            None => (value, UserMeta::default()),
+        };
+        self.set(key, value.clone(), meta)?;
+        Ok(value)
+    }
+
+    /// Reads the current value of a given key with the reader owned by the writer.
+    fn get(&self, key: &str) -> Result<Option<(String, ValueMeta)>> {
+        match self.index.get(key) {
+            Some(entry) => Ok(Some(self.reader.read_value_meta(entry.value().cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the creation time of a key set at `time`, which is kept while it exists.
+    fn created(&self, key: &str, time: u64) -> u64 {
+        self.index
+            .get(key)
+            .map_or(time, |entry| entry.value().created)
+    }
 
-        let thread_pool = P::new(concurrency)?;
-        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
-        for _ in 1..concurrency {
-            reader_pool.push(reader.clone()).unwrap();
+    /// Stores the retained versions of a key and accounts the dropped ones as stale.
+    fn retain_versions(&mut self, key: String, mut versions: Vec<Version>, now: u64) {
+        for version in self.options.history_retention.prune(&mut versions, now) {
+            self.mark_stale(version.cmd_pos);
         }
-        reader_pool.push(reader).unwrap();
+        self.history.insert(key, versions);
+    }
 
-        Ok(KvStore {
-            path,
-            index,
-            writer: Arc::new(Mutex::new(writer)),
-            thread_pool,
-            reader_pool,
-        })
+    /// Drops the versions of all the keys which are not retained anymore, e.g. because
+    /// they are out of the retention window.
+    fn prune_history(&mut self) {
+        let now = now_millis();
+        let history = Arc::clone(&self.history);
+        for entry in history.iter() {
+            self.retain_versions(entry.key().clone(), entry.value().clone(), now);
+        }
     }
-}
 
-/// A single thread reader.
-///
-/// Each `KvStore` instance has its own `KvStoreReader` and
-/// `KvStoreReader`s open the same files separately. So the user
-/// can read concurrently through multiple `KvStore`s in different
-/// threads.
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    // generation of the latest compaction file
-    safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
-}
+    /// Accounts a record appended to the active log.
+    fn mark_written(&mut self, cmd_pos: CommandPos, live: bool) {
+        self.unsnapshotted += cmd_pos.len;
+        let stats = self.gen_stats.entry(cmd_pos.gen).or_default();
+        stats.size = cmd_pos.pos + cmd_pos.len;
+        if live {
+            stats.live += cmd_pos.len;
+        } else {
+            self.uncompacted += cmd_pos.len;
+        }
+    }
 
-impl KvStoreReader {
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
+    /// Accounts a record which is overwritten or removed.
+    fn mark_stale(&mut self, cmd_pos: CommandPos) {
+        if let Some(stats) = self.gen_stats.get_mut(&cmd_pos.gen) {
+            stats.live -= cmd_pos.len;
         }
+        self.uncompacted += cmd_pos.len;
     }
 
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    fn maybe_roll_and_snapshot(&mut self) -> Result<()> {
+        self.maybe_evict()?;
+        if self.writer.pos >= self.options.max_segment_size {
+            self.roll(self.current_gen + 1)?;
+        }
+        let interval = self.options.snapshot_interval;
+        if interval > 0 && self.unsnapshotted >= interval {
+            self.snapshot()?;
+        }
+        self.publish_stats();
+        Ok(())
+    }
+
+    /// Persists the index together with the log position it covers.
+    fn snapshot(&mut self) -> Result<()> {
+        // The snapshot must not cover records which may be lost in a crash.
+        self.flush()?;
+        let (entries, created) = self
+            .index
+            .iter()
+            .map(|entry| {
+                let cmd_pos = entry.value().cmd_pos;
+                ((entry.key().clone(), cmd_pos), entry.value().created)
+            })
+            .unzip();
+        IndexSnapshot {
+            gens: self.manifest.gens.clone(),
+            compaction_gen: self.manifest.compaction_gen,
+            gen: self.current_gen,
+            pos: self.writer.pos,
+            uncompacted: self.uncompacted,
+            next_version: self.next_version,
+            entries,
+            created: Some(created),
+            history: if self.options.history_retention.keeps_history() {
+                Some(
+                    self.history
+                        .iter()
+                        .map(|entry| (entry.key().clone(), entry.value().clone()))
+                        .collect(),
+                )
+            } else {
+                None
+            },
+        }
+        .store(&*self.vfs, &self.path)?;
+        self.unsnapshotted = 0;
+        Ok(())
+    }
+
+    /// Runs an operation which writes to the log.
+    ///
+    /// A failed write may leave a partial record in the active log and the position
+    /// bookkeeping out of sync with the file, so the store turns read-only until
+    /// `recover` succeeds.
+    fn write_op<F, R>(&mut self, op: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(&mut Self) -> Result<R>,
     {
-        self.close_stale_handles();
-        let mut readers = self.readers.borrow_mut();
+        if let Some(reason) = &self.read_only {
+            return Err(KvsError::ReadOnly(reason.clone()));
+        }
+        let res = op(self);
+        if let Err(e) = &res {
+            if is_write_failure(e) {
+                error!("Switching to read-only mode after a write failure: {}", e);
+                self.read_only = Some(e.to_string());
+                // Discarding the partial record now keeps the log valid for a restart.
+                if let Err(e) = self.truncate_active_log() {
+                    warn!("Cannot discard the partial record: {}", e);
+                }
+                self.publish_stats();
+            }
+        }
+        res
+    }
 
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+    /// Leaves the read-only mode if the active log can be written again.
+    fn recover(&mut self) -> Result<()> {
+        if self.read_only.is_some() {
+            self.truncate_active_log()?;
+            self.read_only = None;
+            info!("Leaving read-only mode");
+            self.publish_stats();
         }
+        Ok(())
+    }
 
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    /// Discards the data written to the active log after the last acknowledged record.
+    fn truncate_active_log(&mut self) -> Result<()> {
+        let end = self
+            .gen_stats
+            .get(&self.current_gen)
+            .map_or(0, |stats| stats.size);
+        let file = self.vfs.append(&log_path(&self.path, self.current_gen))?;
+        // The old writer may write its buffered data when it is dropped.
+        drop(mem::replace(&mut self.writer, BufWriterWithPos::new(file)?));
+        self.writer.get_ref().set_len(end)?;
+        self.writer.get_ref().sync_all()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
 
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+    /// Updates the statistics which can be read without the writer lock.
+    fn publish_stats(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.read_only = self.read_only.is_some();
+        stats.uncompacted_bytes = self.uncompacted;
+        stats.generations = self.manifest.gens.len() as u64;
+        stats.disk_size = self.gen_stats.values().map(|stats| stats.size).sum();
     }
 
-    /// Read the log file at the given `CommandPos` and deserialize it to `Command`.
-    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+    /// Flushes the active log and syncs it to disk.
+    fn flush(&mut self) -> Result<()> {
+        self.flush_buffer()?;
+        self.sync()
     }
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> KvStoreReader {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            safe_point: Arc::clone(&self.safe_point),
-            // don't use other KvStoreReader's readers
-            readers: RefCell::new(BTreeMap::new()),
-        }
+    /// Writes the buffered data to the active log.
+    fn flush_buffer(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
     }
-}
 
-struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
-    current_gen: u64,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
-    uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
-}
+    /// Syncs the active log to disk.
+    fn sync(&self) -> Result<()> {
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+    /// Seals the active log and switches to the given newer generation.
+    fn roll(&mut self, current_gen: u64) -> Result<()> {
         self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
+        let writer = new_log_file(&*self.vfs, &self.path, current_gen)?;
+        let mut manifest = self.manifest.clone();
+        manifest.gens.push(current_gen);
+        manifest.store(&*self.vfs, &self.path)?;
+        self.manifest = manifest;
+        self.live_gens().insert(current_gen);
+        self.gen_stats.insert(current_gen, GenStats::default());
+        self.current_gen = current_gen;
+        self.writer = writer;
+        Ok(())
+    }
+
+    fn live_gens(&self) -> &SkipSet<u64> {
+        &self.reader.live_gens
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Picks the generations whose ratio of stale bytes exceeds `compaction_garbage_ratio`
+    /// once the log holds enough stale bytes.
+    fn pick_compaction(&self) -> Vec<u64> {
+        if self.uncompacted <= COMPACTION_THRESHOLD {
+            return Vec::new();
         }
-        Ok(())
+        self.gen_stats
+            .iter()
+            .filter(|(_, stats)| {
+                stats.size > 0
+                    && (stats.size - stats.live) as f64
+                        > stats.size as f64 * self.options.compaction_garbage_ratio
+            })
+            .map(|(&gen, _)| gen)
+            .collect()
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
-            }
+    /// Seals the active log and prepares a compaction of the given generations, which
+    /// copies the records still needed without the writer lock. See `run_compaction`.
+    ///
+    /// The output is numbered between the compacted generations and a new active log,
+    /// so the records written during the copy win on a replay.
+    fn start_compaction(&mut self, gens: Vec<u64>) -> Result<Compaction> {
+        let start = Instant::now();
+        if self.options.history_retention.keeps_history() {
+            self.prune_history();
+        }
+        let total_bytes = gens
+            .iter()
+            .flat_map(|gen| self.gen_stats.get(gen))
+            .map(|stats| stats.live)
+            .sum();
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+        // The output is split into generations of `max_segment_size`, except that
+        // the last reserved one takes the rest.
+        let first_gen = self.current_gen + 1;
+        let last_gen = self.current_gen + total_bytes / self.options.max_segment_size.max(1) + 1;
+        self.write_op(|writer| writer.roll(last_gen + 1))?;
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.compaction_total_bytes = total_bytes;
+            stats.compaction_done_bytes = 0;
         }
+
+        // A tombstone must be kept while an older generation may contain the removed key.
+        let oldest_kept_gen = self
+            .manifest
+            .gens
+            .iter()
+            .copied()
+            .find(|gen| !gens.contains(gen));
+        Ok(Compaction {
+            gens,
+            first_gen,
+            last_gen,
+            oldest_kept_gen,
+            reader: self.reader.clone(),
+            path: Arc::clone(&self.path),
+            vfs: Arc::clone(&self.vfs),
+            index: Arc::clone(&self.index),
+            history: Arc::clone(&self.history),
+            max_segment_size: self.options.max_segment_size,
+            stats: Arc::clone(&self.stats),
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            start,
+        })
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
-        let compaction_gen = self.current_gen + 1;
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+    /// Publishes the output of a compaction and drops the compacted generations.
+    fn finish_compaction(
+        &mut self,
+        compaction: Compaction,
+        output: Result<CompactionOutput>,
+    ) -> Result<()> {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.compaction_total_bytes = 0;
+            stats.compaction_done_bytes = 0;
+            if output.is_ok() {
+                stats.compactions += 1;
+                stats.compaction_time += compaction.start.elapsed();
+            }
+        }
+        let output = output?;
+        let gens = &compaction.gens;
 
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        // Until the manifest lists them, the compaction files are orphans.
+        let mut manifest = Manifest {
+            gens: self
+                .manifest
+                .gens
+                .iter()
+                .copied()
+                .filter(|gen| !gens.contains(gen))
+                .chain(output.gen_sizes.keys().copied())
+                .collect(),
+            compaction_gen: compaction.first_gen,
+        };
+        manifest.gens.sort_unstable();
+        self.write_op(|writer| manifest.store(&*writer.vfs, &writer.path))?;
+        self.manifest = manifest;
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+        // The index is updated after the compaction files are published because readers
+        // cannot open them before that.
+        let mut compaction_gen_stats: BTreeMap<u64, GenStats> = output
+            .gen_sizes
+            .iter()
+            .map(|(&gen, &size)| (gen, GenStats { size, live: 0 }))
+            .collect();
+        for &gen in compaction_gen_stats.keys() {
+            self.live_gens().insert(gen);
+        }
+        for (key, old_pos, new_pos) in output.moved {
+            // Records overwritten or removed during the copy are stale in the output.
+            // Kept tombstones hold no data, so like written ones they are not live.
+            let live = match key {
+                Some(key) => self.relocate(key, old_pos, new_pos),
+                None => false,
+            };
+            if let Some(stats) = compaction_gen_stats.get_mut(&new_pos.gen) {
+                if live {
+                    stats.live += new_pos.len;
+                }
+            }
         }
-        compaction_writer.flush()?;
+        self.gen_stats.extend(compaction_gen_stats);
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
+        for gen in gens {
+            self.live_gens().remove(gen);
+            self.gen_stats.remove(gen);
+        }
         self.reader.close_stale_handles();
+        if let Some(mmaps) = &self.reader.mmaps {
+            mmaps.retain(self.live_gens());
+        }
 
         // remove stale log files
         // Note that actually these files are not deleted immediately because `KvStoreReader`s
         // still keep open file handles. When `KvStoreReader` is used next time, it will clear
         // its stale file handles. On Unix, the files will be deleted after all the handles
         // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
+        // to be deleted as orphans in the next open.
+        for &stale_gen in gens {
             let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
+            if let Err(e) = self.vfs.remove_file(&file_path) {
                 error!("{:?} cannot be deleted: {}", file_path, e);
             }
         }
-        self.uncompacted = 0;
+        self.uncompacted = self
+            .gen_stats
+            .values()
+            .map(|stats| stats.size - stats.live)
+            .sum();
 
+        // The previous snapshot points to the rewritten generations.
+        if self.options.snapshot_interval > 0 {
+            self.snapshot()?;
+        }
+        self.publish_stats();
         Ok(())
     }
+
+    /// Points the index and the history of a key to the new position of a record.
+    ///
+    /// Returns whether any of them pointed to the old position.
+    fn relocate(&self, key: String, old_pos: CommandPos, new_pos: CommandPos) -> bool {
+        let mut relocated = false;
+        if let Some(entry) = self.index.get(&key) {
+            if entry.value().cmd_pos.same_record(old_pos) {
+                let (created, last_access) = (entry.value().created, entry.value().last_access());
+                self.index
+                    .insert(key.clone(), IndexEntry::new(new_pos, created, last_access));
+                relocated = true;
+            }
+        }
+        if let Some(entry) = self.history.get(&key) {
+            let mut versions = entry.value().clone();
+            for version in &mut versions {
+                if version.cmd_pos.same_record(old_pos) {
+                    version.cmd_pos = new_pos;
+                    relocated = true;
+                }
+            }
+            self.history.insert(key, versions);
+        }
+        relocated
+    }
+}
+
+/// Runs a write under the writer lock, followed by a compaction if the write left
+/// enough stale bytes.
+///
+/// A failed compaction is only logged, since the write is already applied and a
+/// caller retrying it on an error would apply it twice.
+fn write<F, R>(writer: &Mutex<KvStoreWriter>, op: F) -> Result<R>
+where
+    F: FnOnce(&mut KvStoreWriter) -> Result<R>,
+{
+    let (res, needs_compaction) = {
+        let mut writer = writer.lock().unwrap();
+        let res = writer.write_op(op)?;
+        (res, writer.uncompacted > COMPACTION_THRESHOLD)
+    };
+    if needs_compaction {
+        if let Err(e) = run_compaction(writer, false, KvStoreWriter::pick_compaction) {
+            error!("Compaction failed: {}", e);
+        }
+    }
+    Ok(res)
+}
+
+/// Compacts the generations picked by `pick`.
+///
+/// The writer lock is only held to start the compaction and to publish its output,
+/// so other writes go on while the records are copied at the compaction rate limit.
+/// Only one compaction runs at a time. If another one is running, it is waited for
+/// if `wait` is true and nothing is done otherwise.
+///
+/// Only a failure writing the active log or the manifest turns the store read-only.
+/// A failed copy leaves the compacted generations in place and the store writable.
+fn run_compaction<F>(writer: &Mutex<KvStoreWriter>, wait: bool, pick: F) -> Result<()>
+where
+    F: FnOnce(&KvStoreWriter) -> Vec<u64>,
+{
+    let compaction_lock = Arc::clone(&writer.lock().unwrap().compaction_lock);
+    let _compacting = if wait {
+        compaction_lock.lock().unwrap()
+    } else {
+        match compaction_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(()),
+        }
+    };
+
+    let compaction = {
+        let mut writer = writer.lock().unwrap();
+        let gens = pick(&writer);
+        if gens.is_empty() {
+            return Ok(());
+        }
+        writer.start_compaction(gens)?
+    };
+    let output = compaction.run();
+    writer.lock().unwrap().finish_compaction(compaction, output)
+}
+
+/// A compaction of sealed generations started by `KvStoreWriter::start_compaction`.
+struct Compaction {
+    gens: Vec<u64>,
+    // the generation numbers reserved for the output
+    first_gen: u64,
+    last_gen: u64,
+    // the oldest live generation which is not compacted
+    oldest_kept_gen: Option<u64>,
+    reader: KvStoreReader,
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    index: Arc<SkipMap<String, IndexEntry>>,
+    history: Arc<SkipMap<String, Vec<Version>>>,
+    max_segment_size: u64,
+    stats: Arc<Mutex<EngineStats>>,
+    rate_limiter: Arc<RateLimiter>,
+    start: Instant,
+}
+
+/// The records written by a `Compaction`.
+struct CompactionOutput {
+    // the size of each output generation
+    gen_sizes: BTreeMap<u64, u64>,
+    // the key of each record, or `None` for a tombstone, with its old and new positions
+    moved: Vec<(Option<String>, CommandPos, CommandPos)>,
+}
+
+impl Compaction {
+    /// Copies the records still needed into the reserved generations.
+    ///
+    /// The output is written to temporary files which are renamed only after they are
+    /// durable, so a crash never leaves a partial compaction file.
+    fn run(&self) -> Result<CompactionOutput> {
+        let mut compaction_gen = self.first_gen;
+        let mut compaction_writer = new_compaction_file(&*self.vfs, &self.path, compaction_gen)?;
+        let mut output = CompactionOutput {
+            gen_sizes: BTreeMap::new(),
+            moved: Vec::new(),
+        };
+
+        for &gen in &self.gens {
+            let keep_tombstones = matches!(self.oldest_kept_gen, Some(oldest) if oldest < gen);
+            let mut reader = BufReaderWithPos::new(self.vfs.open(&log_path(&self.path, gen))?)?;
+            let records = needed_records(
+                gen,
+                &mut reader,
+                &self.index,
+                &self.history,
+                keep_tombstones,
+                &self.rate_limiter,
+            )?;
+
+            for (key, cmd_pos) in records {
+                if compaction_writer.pos >= self.max_segment_size && compaction_gen < self.last_gen
+                {
+                    seal_compaction_file(
+                        &*self.vfs,
+                        &self.path,
+                        compaction_gen,
+                        compaction_writer,
+                    )?;
+                    compaction_gen += 1;
+                    compaction_writer =
+                        new_compaction_file(&*self.vfs, &self.path, compaction_gen)?;
+                }
+                let pos = compaction_writer.pos;
+                let rate_limiter = &self.rate_limiter;
+                let len = self.reader.read_and(cmd_pos, |entry_reader| {
+                    Ok(io::copy(
+                        &mut RateLimited::new(entry_reader, rate_limiter),
+                        &mut RateLimited::new(&mut compaction_writer, rate_limiter),
+                    )?)
+                })?;
+                self.stats.lock().unwrap().compaction_done_bytes += len;
+                *output.gen_sizes.entry(compaction_gen).or_default() += len;
+                output.moved.push((
+                    key,
+                    cmd_pos,
+                    CommandPos::from((compaction_gen, pos..pos + len)),
+                ));
+            }
+        }
+        if compaction_writer.pos > 0 {
+            seal_compaction_file(&*self.vfs, &self.path, compaction_gen, compaction_writer)?;
+        } else {
+            drop(compaction_writer);
+            self.vfs
+                .remove_file(&compaction_tmp_path(&self.path, compaction_gen))?;
+        }
+        self.vfs.sync_dir(&self.path)?;
+        Ok(output)
+    }
+}
+
+/// Sends a chunk of a streamed value, waiting at most `STREAM_SEND_TIMEOUT` for the
+/// receiver.
+fn send_chunk(tx: &Sender<Result<Vec<u8>>>, chunk: Vec<u8>) -> Result<()> {
+    smol::block_on(smol::future::or(
+        async {
+            tx.send(Ok(chunk))
+                .await
+                .map_err(|_| KvsError::StringError("Receiving end is dropped".to_owned()))
+        },
+        async {
+            Timer::after(STREAM_SEND_TIMEOUT).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the receiver did not take a chunk of the value in time",
+            )
+            .into())
+        },
+    ))
+}
+
+/// Returns whether an error means that the log holds data which is not a record.
+fn is_corruption(e: &KvsError) -> bool {
+    matches!(e, KvsError::Serde(e) if !e.is_io())
+}
+
+/// Returns whether an error means that the log may not hold what was written.
+fn is_write_failure(e: &KvsError) -> bool {
+    match e {
+        KvsError::Io(_) => true,
+        KvsError::Serde(e) => e.is_io(),
+        _ => false,
+    }
 }
 
 /// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn sorted_gen_list(vfs: &dyn Vfs, path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = vfs
+        .read_dir(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -386,18 +1850,66 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
+/// Create a temporary file for the compaction output with given generation number.
+fn new_compaction_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
+    BufWriterWithPos::new(vfs.create(&compaction_tmp_path(path, gen))?)
+}
+
+/// Make the compaction output durable and rename it to the log file of its generation.
+///
+/// The directory must be synced afterwards to make the rename durable.
+fn seal_compaction_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    mut writer: BufWriterWithPos<Box<dyn VfsFile>>,
+) -> Result<()> {
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    vfs.rename(&compaction_tmp_path(path, gen), &log_path(path, gen))?;
+    Ok(())
+}
+
+/// Removes log files which are not listed in the manifest, unfinished compaction files
+/// and staging files of streamed values.
+///
+/// Failing to delete an orphan file is not fatal because it is never replayed.
+fn remove_orphan_files(
+    vfs: &dyn Vfs,
+    path: &Path,
+    disk_gen_list: &[u64],
+    live_gen_list: &[u64],
+) -> Result<()> {
+    let orphan_logs = disk_gen_list
+        .iter()
+        .filter(|gen| !live_gen_list.contains(gen))
+        .map(|&gen| log_path(path, gen));
+    let tmp_files = vfs.read_dir(path)?.into_iter().filter(|path| {
+            matches!(path.file_name().and_then(OsStr::to_str), Some(s) if s.ends_with(".log.tmp") || s.ends_with(".value.tmp"))
+        });
+    for file_path in orphan_logs.chain(tmp_files) {
+        warn!("Removing orphan file {:?}", file_path);
+        if let Err(e) = vfs.remove_file(&file_path) {
+            error!("{:?} cannot be deleted: {}", file_path, e);
+        }
+    }
+    Ok(())
+}
+
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+fn new_log_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
     let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
+    let writer = BufWriterWithPos::new(vfs.append(&path)?)?;
     Ok(writer)
 }
 
@@ -405,33 +1917,83 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+fn compaction_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", gen))
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// The versions of each key are collected as well if `keeps_history` is true. If
+/// `allow_torn` is true, a record cut short at the end of the log is not an error and
+/// its offset is returned in `GenIndex::torn` instead.
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    start: u64,
+    reader: &mut BufReaderWithPos<Box<dyn VfsFile>>,
+    keeps_history: bool,
+    allow_torn: bool,
+) -> Result<GenIndex> {
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
 
+    let mut entries: HashMap<String, Option<GenEntry>> = HashMap::new();
+    let mut versions: HashMap<String, GenVersions> = HashMap::new();
+    let mut max_version = 0;
     // number of bytes that can be saved after a compaction
     let mut uncompacted = 0;
+    let mut torn = None;
 
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+        // the offset is relative to where the stream starts
+        let new_pos = start + stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Err(e) if allow_torn && e.is_eof() => {
+                torn = Some(pos);
+                break;
+            }
+            cmd => cmd?,
+        };
+        match cmd {
+            Command::Set {
+                key,
+                version,
+                time,
+                created,
+                ..
+            } => {
+                let cmd_pos = CommandPos::from((gen, pos..new_pos));
+                let created = created_time(created, time);
+                max_version = max_version.max(version);
+                if keeps_history {
+                    versions
+                        .entry(key.clone())
+                        .or_default()
+                        .versions
+                        .push(Version {
+                            version,
+                            time,
+                            cmd_pos,
+                        });
+                }
+                if let Some(Some(old)) = entries.insert(
+                    key,
+                    Some(GenEntry {
+                        cmd_pos,
+                        version,
+                        created,
+                    }),
+                ) {
+                    uncompacted += old.cmd_pos.len;
                 }
-                index.insert(key, (gen, pos..new_pos).into());
             }
             Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+                if keeps_history {
+                    let gen_versions = versions.entry(key.clone()).or_default();
+                    gen_versions.removed = true;
+                    gen_versions.versions.clear();
+                }
+                if let Some(Some(old)) = entries.insert(key, None) {
+                    uncompacted += old.cmd_pos.len;
                 }
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
@@ -440,19 +2002,197 @@ fn load(
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    Ok(GenIndex {
+        entries,
+        versions,
+        max_version,
+        uncompacted,
+        lost_keys: HashSet::new(),
+        torn,
+    })
+}
+
+/// The last command of each key in a single generation.
+struct GenIndex {
+    // `None` if the last command of the key is a "remove"
+    entries: HashMap<String, Option<GenEntry>>,
+    // the versions of each key if the history is retained
+    versions: HashMap<String, GenVersions>,
+    // the highest version number of the "set" commands
+    max_version: u64,
+    // bytes superseded by later commands in the same generation
+    uncompacted: u64,
+    // keys whose last command in the generation was skipped by a salvage
+    lost_keys: HashSet<String>,
+    // the offset of a record cut short at the end of the generation, if any
+    torn: Option<u64>,
+}
+
+impl GenIndex {
+    /// Applies the commands on top of the index built from the older generations.
+    ///
+    /// A compaction may move retained older versions of a key into a generation newer
+    /// than the one of its current value, so "set" commands are merged by their version
+    /// and `index_versions` tracks the version of each indexed one.
+    ///
+    /// Returns the number of bytes that can be saved after a compaction. The keys of
+    /// the lost commands in the older generations are replaced by the ones in this
+    /// generation.
+    fn merge_into(
+        self,
+        index: &SkipMap<String, IndexEntry>,
+        index_versions: &mut HashMap<String, u64>,
+        history: &SkipMap<String, Vec<Version>>,
+        lost_keys: &mut BTreeSet<String>,
+    ) -> u64 {
+        let mut uncompacted = self.uncompacted;
+        for (key, gen_versions) in self.versions {
+            let mut versions = match history.get(&key) {
+                Some(entry) if !gen_versions.removed => entry.value().clone(),
+                _ => Vec::new(),
+            };
+            versions.extend(gen_versions.versions);
+            if versions.is_empty() {
+                history.remove(&key);
+            } else {
+                versions.sort_by_key(|version| version.version);
+                history.insert(key, versions);
+            }
+        }
+        for (key, entry) in self.entries {
+            lost_keys.remove(&key);
+            let old_cmd = match entry {
+                // Records written before versions were introduced have version 0, so the
+                // newer generation wins a tie.
+                Some(entry) if entry.version < index_versions.get(&key).copied().unwrap_or(0) => {
+                    Some(entry.cmd_pos)
+                }
+                Some(entry) => {
+                    let old_cmd = index.get(&key).map(|old| old.value().cmd_pos);
+                    index_versions.insert(key.clone(), entry.version);
+                    index.insert(key, IndexEntry::new(entry.cmd_pos, entry.created, 0));
+                    old_cmd
+                }
+                None => {
+                    index_versions.remove(&key);
+                    index.remove(&key).map(|old| old.value().cmd_pos)
+                }
+            };
+            if let Some(old_cmd) = old_cmd {
+                uncompacted += old_cmd.len;
+            }
+        }
+        lost_keys.extend(self.lost_keys);
+        uncompacted
+    }
+}
+
+/// The last "set" command of a key in a single generation.
+#[derive(Clone, Copy)]
+struct GenEntry {
+    cmd_pos: CommandPos,
+    version: u64,
+    // milliseconds since the Unix epoch when the key was created
+    created: u64,
+}
+
+/// The "set" commands of a key in a single generation.
+#[derive(Default)]
+struct GenVersions {
+    // whether the versions of the older generations are removed
+    removed: bool,
+    // the versions after the last "remove" command
+    versions: Vec<Version>,
+}
+
+/// Scans a log file for the records which must survive a compaction of its generation.
+///
+/// A "set" record is needed if the index or the history still points to it. A "remove"
+/// record is needed if `keep_tombstones` is true and the key has not been set again.
+/// Returns the keys of the needed "set" records and the positions of all the needed
+/// records.
+fn needed_records(
+    gen: u64,
+    reader: &mut BufReaderWithPos<Box<dyn VfsFile>>,
+    index: &SkipMap<String, IndexEntry>,
+    history: &SkipMap<String, Vec<Version>>,
+    keep_tombstones: bool,
+    rate_limiter: &RateLimiter,
+) -> Result<Vec<(Option<String>, CommandPos)>> {
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut stream =
+        Deserializer::from_reader(RateLimited::new(reader, rate_limiter)).into_iter::<Command>();
+    let mut records = Vec::new();
+
+    while let Some(cmd) = stream.next() {
+        let new_pos = stream.byte_offset() as u64;
+        let cmd_pos = CommandPos::from((gen, pos..new_pos));
+        match cmd? {
+            Command::Set { key, .. } => {
+                let indexed = matches!(index.get(&key), Some(entry) if entry.value().cmd_pos.same_record(cmd_pos));
+                let retained = matches!(history.get(&key), Some(entry) if entry
+                    .value()
+                    .iter()
+                    .any(|version| version.cmd_pos.same_record(cmd_pos)));
+                if indexed || retained {
+                    records.push((Some(key), cmd_pos));
+                }
+            }
+            Command::Remove { key } => {
+                if keep_tombstones && !index.contains_key(&key) {
+                    records.push((None, cmd_pos));
+                }
+            }
+        }
+        pos = new_pos;
+    }
+    Ok(records)
 }
 
 /// Struct representing a command.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        // missing in the records written before versions were introduced
+        #[serde(default)]
+        version: u64,
+        // milliseconds since the Unix epoch
+        #[serde(default)]
+        time: u64,
+        // milliseconds since the Unix epoch when the key was set while it did not exist,
+        // missing in the records written before creation times were introduced
+        #[serde(default)]
+        created: u64,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        flags: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+    },
+    Remove {
+        key: String,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(
+        key: String,
+        value: String,
+        version: u64,
+        time: u64,
+        created: u64,
+        meta: UserMeta,
+    ) -> Command {
+        Command::Set {
+            key,
+            value,
+            version,
+            time,
+            created,
+            flags: meta.flags,
+            content_type: meta.content_type,
+        }
     }
 
     fn remove(key: String) -> Command {
@@ -460,14 +2200,95 @@ impl Command {
     }
 }
 
+/// Returns the creation time of a "set" record, which is its write time in the records
+/// written before creation times were introduced.
+fn created_time(created: u64, time: u64) -> u64 {
+    if created == 0 {
+        time
+    } else {
+        created
+    }
+}
+
+fn is_zero(flags: &u32) -> bool {
+    *flags == 0
+}
+
+/// Byte accounting of a generation.
+#[derive(Debug, Clone, Copy, Default)]
+struct GenStats {
+    // the size of the log file
+    size: u64,
+    // the number of bytes of the records which are still needed
+    live: u64,
+}
+
+/// The current value of a key in the in-memory index.
+#[derive(Debug)]
+struct IndexEntry {
+    cmd_pos: CommandPos,
+    // milliseconds since the Unix epoch when the key was created, which is kept by
+    // the following writes
+    created: u64,
+    // the access clock at the last write, or at the last read in cache mode, or 0 if the
+    // key was loaded from disk
+    last_access: AtomicU64,
+}
+
+impl IndexEntry {
+    fn new(cmd_pos: CommandPos, created: u64, last_access: u64) -> Self {
+        IndexEntry {
+            cmd_pos,
+            created,
+            last_access: AtomicU64::new(last_access),
+        }
+    }
+
+    /// Records a read of the key.
+    fn touch(&self, access_clock: &AtomicU64) {
+        self.last_access
+            .store(tick(access_clock), Ordering::Relaxed);
+    }
+
+    fn last_access(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the position of the current value of a key, recording the read with the
+/// given access clock if there is one.
+fn lookup(
+    index: &SkipMap<String, IndexEntry>,
+    key: &str,
+    access_clock: Option<&AtomicU64>,
+) -> Option<CommandPos> {
+    let entry = index.get(key)?;
+    if let Some(access_clock) = access_clock {
+        entry.value().touch(access_clock);
+    }
+    Some(entry.value().cmd_pos)
+}
+
+/// Advances the access clock and returns its new time.
+fn tick(access_clock: &AtomicU64) -> u64 {
+    access_clock.fetch_add(1, Ordering::Relaxed) + 1
+}
+
 /// Represents the position and length of a json-serialized command in the log.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
 }
 
+impl CommandPos {
+    /// Returns whether both positions point to the same record.
+    fn same_record(self, other: CommandPos) -> bool {
+        self.gen == other.gen && self.pos == other.pos
+    }
+}
+
 impl From<(u64, Range<u64>)> for CommandPos {
     fn from((gen, range): (u64, Range<u64>)) -> Self {
         CommandPos {
@@ -521,6 +2342,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
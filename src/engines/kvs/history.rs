@@ -0,0 +1,81 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::CommandPos;
+
+/// How many versions of each key a `KvStore` retains.
+///
+/// The current value is always retained. A removal drops all the versions of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRetention {
+    /// Retains the given number of the latest versions of each key.
+    Versions(usize),
+    /// Retains the versions written within the given duration.
+    Window(Duration),
+}
+
+impl HistoryRetention {
+    /// Returns whether versions older than the current one are retained.
+    pub(super) fn keeps_history(self) -> bool {
+        match self {
+            HistoryRetention::Versions(versions) => versions > 1,
+            HistoryRetention::Window(_) => true,
+        }
+    }
+
+    /// Removes the versions which are not retained anymore from the given ones, oldest
+    /// first, and returns them.
+    pub(super) fn prune(self, versions: &mut Vec<Version>, now: u64) -> Vec<Version> {
+        let expired = match self {
+            HistoryRetention::Versions(retained) => versions.len().saturating_sub(retained.max(1)),
+            HistoryRetention::Window(window) => {
+                let since = now.saturating_sub(window.as_millis() as u64);
+                let expired = versions.iter().take_while(|v| v.time < since).count();
+                expired.min(versions.len().saturating_sub(1))
+            }
+        };
+        versions.drain(..expired).collect()
+    }
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention::Versions(1)
+    }
+}
+
+/// A retained version of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// The version number.
+    ///
+    /// Versions are numbered by a sequence shared by all the keys, so the versions of
+    /// a key increase but are not consecutive.
+    pub version: u64,
+    /// The time when the version was written.
+    pub time: SystemTime,
+    /// The value of the key in this version.
+    pub value: String,
+}
+
+/// The location of a retained version in the log.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct Version {
+    pub version: u64,
+    // milliseconds since the Unix epoch
+    pub time: u64,
+    pub cmd_pos: CommandPos,
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Converts milliseconds since the Unix epoch to a `SystemTime`.
+pub(super) fn system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
@@ -0,0 +1,56 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, Vfs};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+
+/// The `Manifest` records the log generations which make up the store.
+///
+/// It is the source of truth of `KvStore::open`. Log files which are not listed
+/// in the manifest, e.g. a half-written compaction file or a stale file which failed
+/// to be deleted, are never replayed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    // live generation numbers in ascending order
+    pub gens: Vec<u64>,
+    // first generation written by the latest compaction, or 0 if there is none
+    pub compaction_gen: u64,
+}
+
+impl Manifest {
+    /// Loads the manifest in the given directory.
+    ///
+    /// Returns `None` if the directory has no manifest.
+    pub fn load(vfs: &dyn Vfs, dir: &Path) -> Result<Option<Manifest>> {
+        let mut file = match vfs.open(&manifest_path(dir)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Atomically replaces the manifest in the given directory.
+    ///
+    /// The manifest is written to a temporary file which is synced and then renamed
+    /// over the old one, so a crash leaves either the old or the new manifest.
+    pub fn store(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_TMP_FILE);
+        let mut file = vfs.create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        vfs.rename(&tmp_path, &manifest_path(dir))?;
+        vfs.sync_dir(dir)?;
+        Ok(())
+    }
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE)
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use crossbeam_skiplist::{SkipMap, SkipSet};
+
+use super::log_path;
+use crate::vfs::MappedFile;
+use crate::{Result, Vfs};
+
+/// Memory maps of immutable generations shared by all the readers.
+///
+/// A generation is mapped on the first read and unmapped once a compaction rewrites it.
+#[derive(Default)]
+pub(super) struct MmapCache {
+    maps: SkipMap<u64, MappedFile>,
+}
+
+impl MmapCache {
+    /// Returns the memory map of the given generation, mapping it if necessary.
+    ///
+    /// The generation must not be written to anymore.
+    pub fn get(&self, vfs: &dyn Vfs, dir: &Path, gen: u64) -> Result<MappedFile> {
+        if let Some(entry) = self.maps.get(&gen) {
+            return Ok(entry.value().clone());
+        }
+        // Log files of non-active generations are never modified or truncated.
+        let mmap = vfs.map(&log_path(dir, gen))?;
+        Ok(self.maps.get_or_insert(gen, mmap).value().clone())
+    }
+
+    /// Unmaps the generations which are no longer live.
+    pub fn retain(&self, live_gens: &SkipSet<u64>) {
+        for entry in self.maps.iter() {
+            if !live_gens.contains(entry.key()) {
+                entry.remove();
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the longest time to sleep at once, so that rate changes take effect quickly
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// A token bucket limiting the number of bytes per second.
+///
+/// The bucket holds at most one second worth of tokens. A request larger than the
+/// available tokens is granted as soon as the bucket is not empty and puts the bucket
+/// into debt, which the following requests wait for.
+pub(super) struct RateLimiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    // 0 means unlimited
+    bytes_per_sec: u64,
+    // may be negative after a large request
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a `RateLimiter`. A rate of 0 means unlimited.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            state: Mutex::new(State {
+                bytes_per_sec,
+                available: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Changes the rate. A rate of 0 means unlimited.
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.refill();
+        state.bytes_per_sec = bytes_per_sec;
+        state.available = state.available.min(bytes_per_sec as f64);
+    }
+
+    /// Blocks the current thread until `bytes` can be consumed.
+    pub fn acquire(&self, bytes: u64) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if state.bytes_per_sec == 0 {
+                    return;
+                }
+                state.refill();
+                if state.available > 0.0 {
+                    state.available -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-state.available / state.bytes_per_sec as f64)
+            };
+            thread::sleep(cmp::min(wait, MAX_WAIT));
+        }
+    }
+}
+
+impl State {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let capacity = self.bytes_per_sec as f64;
+        self.available = (self.available + elapsed * capacity).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Wraps a reader or a writer to consume tokens of a `RateLimiter` for the bytes
+/// read or written.
+pub(super) struct RateLimited<'a, T> {
+    inner: T,
+    limiter: &'a RateLimiter,
+}
+
+impl<'a, T> RateLimited<'a, T> {
+    pub fn new(inner: T, limiter: &'a RateLimiter) -> Self {
+        RateLimited { inner, limiter }
+    }
+}
+
+impl<'a, R: Read> Read for RateLimited<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.limiter.acquire(len as u64);
+        Ok(len)
+    }
+}
+
+impl<'a, W: Write> Write for RateLimited<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.limiter.acquire(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,93 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use smol::channel::{self, Receiver, Sender};
+
+use super::KvStoreReader;
+use crate::Result;
+
+/// A pool of `KvStoreReader`s shared by concurrent reads.
+///
+/// Readers are created on demand up to `max_readers`. Each reader keeps a file handle
+/// per generation it has read, so the limit bounds the number of open file handles.
+/// When all the readers are in use, `acquire` waits until one is released.
+pub(super) struct ReaderPool {
+    // cloned to create a new reader
+    template: Mutex<KvStoreReader>,
+    created: AtomicUsize,
+    max_readers: usize,
+    idle_tx: Sender<KvStoreReader>,
+    idle_rx: Receiver<KvStoreReader>,
+}
+
+impl ReaderPool {
+    /// Creates a `ReaderPool` holding at most `max_readers` readers. It is at least 1.
+    ///
+    /// The given reader is the first idle one and new readers are cloned from it.
+    pub fn new(reader: KvStoreReader, max_readers: usize) -> Self {
+        let max_readers = max_readers.max(1);
+        let (idle_tx, idle_rx) = channel::bounded(max_readers);
+        let template = Mutex::new(reader.clone());
+        idle_tx.try_send(reader).unwrap();
+        ReaderPool {
+            template,
+            created: AtomicUsize::new(1),
+            max_readers,
+            idle_tx,
+            idle_rx,
+        }
+    }
+
+    /// Takes an idle reader, creating a new one if the limit is not reached yet.
+    ///
+    /// The reader returns to the pool when the `PooledReader` is dropped.
+    pub async fn acquire(self: &Arc<Self>) -> Result<PooledReader> {
+        let reader = match self.idle_rx.try_recv() {
+            Ok(reader) => reader,
+            Err(_) if self.reserve() => self.template.lock().unwrap().clone(),
+            Err(_) => self.idle_rx.recv().await?,
+        };
+        Ok(PooledReader {
+            reader: Some(reader),
+            pool: Arc::clone(self),
+        })
+    }
+
+    /// Counts a new reader in if the limit is not reached yet.
+    fn reserve(&self) -> bool {
+        let max_readers = self.max_readers;
+        self.created
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |created| {
+                if created < max_readers {
+                    Some(created + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// A `KvStoreReader` borrowed from a `ReaderPool`.
+pub(super) struct PooledReader {
+    reader: Option<KvStoreReader>,
+    pool: Arc<ReaderPool>,
+}
+
+impl Deref for PooledReader {
+    type Target = KvStoreReader;
+
+    fn deref(&self) -> &KvStoreReader {
+        self.reader.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledReader {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            // The channel can hold all the readers, so it never fails.
+            let _ = self.pool.idle_tx.try_send(reader);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Deserializer;
+
+use super::{compaction_tmp_path, log_path, Command};
+use crate::{Result, Vfs};
+
+// Every record is a JSON object starting with one of these. Strings escape their quotes,
+// so they cannot occur inside an intact record.
+const RECORD_STARTS: [&[u8]; 2] = [b"{\"Set\":", b"{\"Remove\":"];
+const KEY_FIELD: &[u8] = b"\"key\":";
+
+/// What `KvStore::open` skipped in salvage mode.
+#[derive(Debug, Clone, Default)]
+pub struct SalvageReport {
+    /// The generations which had corrupt regions. A copy of each damaged log file is
+    /// kept in the directory with a `corrupt` extension.
+    pub damaged_gens: Vec<u64>,
+    /// The number of records in the corrupt regions, counting a region without
+    /// a recognizable record as one.
+    pub lost_records: u64,
+    /// The number of bytes in the corrupt regions.
+    pub lost_bytes: u64,
+    /// The keys whose latest command may be lost, in ascending order.
+    pub lost_keys: Vec<String>,
+}
+
+/// The intact and the lost records of a damaged generation.
+#[derive(Debug, Default)]
+pub(super) struct SalvagedGen {
+    pub lost_records: u64,
+    pub lost_bytes: u64,
+    // keys of the lost records which are not followed by an intact record of the key
+    pub lost_keys: HashSet<String>,
+}
+
+/// Rewrites a generation so that it only holds its intact records.
+///
+/// A corrupt region is skipped up to the next offset where an intact record starts.
+/// The damaged file is copied aside before the rewritten one replaces it.
+pub(super) fn salvage_gen(vfs: &dyn Vfs, dir: &Path, gen: u64) -> Result<SalvagedGen> {
+    let mut bytes = Vec::new();
+    vfs.open(&log_path(dir, gen))?.read_to_end(&mut bytes)?;
+
+    let mut salvaged = SalvagedGen::default();
+    let mut intact = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if let Some((cmd, len)) = parse_record(&bytes[pos..]) {
+            salvaged.lost_keys.remove(command_key(&cmd));
+            intact.extend_from_slice(&bytes[pos..pos + len]);
+            pos += len;
+            continue;
+        }
+        let end = next_record(&bytes, pos + 1);
+        let region = &bytes[pos..end];
+        let records = (0..region.len())
+            .filter(|&i| RECORD_STARTS.iter().any(|s| region[i..].starts_with(s)))
+            .count();
+        salvaged.lost_records += records.max(1) as u64;
+        salvaged.lost_bytes += region.len() as u64;
+        salvaged.lost_keys.extend(keys_in(region));
+        pos = end;
+    }
+
+    let mut file = vfs.create(&corrupt_path(dir, gen))?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    let tmp_path = compaction_tmp_path(dir, gen);
+    let mut file = vfs.create(&tmp_path)?;
+    file.write_all(&intact)?;
+    file.sync_all()?;
+    vfs.rename(&tmp_path, &log_path(dir, gen))?;
+    vfs.sync_dir(dir)?;
+    Ok(salvaged)
+}
+
+/// Parses the record at the start of the given bytes and returns it with its length.
+fn parse_record(bytes: &[u8]) -> Option<(Command, usize)> {
+    let mut stream = Deserializer::from_slice(bytes).into_iter::<Command>();
+    match stream.next() {
+        Some(Ok(cmd)) => Some((cmd, stream.byte_offset())),
+        _ => None,
+    }
+}
+
+/// Returns the offset of the first intact record at or after `from`, or the length
+/// of the bytes if there is none.
+fn next_record(bytes: &[u8], from: usize) -> usize {
+    (from..bytes.len())
+        .find(|&pos| {
+            RECORD_STARTS.iter().any(|s| bytes[pos..].starts_with(s))
+                && parse_record(&bytes[pos..]).is_some()
+        })
+        .unwrap_or(bytes.len())
+}
+
+/// Returns the keys which can still be read from a corrupt region.
+fn keys_in(region: &[u8]) -> Vec<String> {
+    (0..region.len())
+        .filter(|&i| region[i..].starts_with(KEY_FIELD))
+        .filter_map(|i| {
+            Deserializer::from_slice(&region[i + KEY_FIELD.len()..])
+                .into_iter::<String>()
+                .next()?
+                .ok()
+        })
+        .collect()
+}
+
+fn command_key(cmd: &Command) -> &str {
+    match cmd {
+        Command::Set { key, .. } | Command::Remove { key } => key,
+    }
+}
+
+fn corrupt_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.corrupt", gen))
+}
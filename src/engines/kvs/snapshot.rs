@@ -0,0 +1,87 @@
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{log_path, CommandPos, Manifest, Version};
+use crate::{Result, Vfs};
+
+const SNAPSHOT_FILE: &str = "INDEX";
+const SNAPSHOT_TMP_FILE: &str = "INDEX.tmp";
+
+/// An `IndexSnapshot` is a persisted copy of the in-memory index.
+///
+/// It covers the log up to `pos` in the active generation `gen`, so `KvStore::open`
+/// only needs to replay the records written after it. A compaction rewrites the
+/// generations the entries point to, so a snapshot taken before the latest compaction
+/// is never used.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct IndexSnapshot {
+    // live generation numbers when the snapshot was taken, ending with `gen`
+    pub gens: Vec<u64>,
+    pub compaction_gen: u64,
+    pub gen: u64,
+    pub pos: u64,
+    pub uncompacted: u64,
+    #[serde(default)]
+    pub next_version: u64,
+    pub entries: Vec<(String, CommandPos)>,
+    // the creation time of each entry, missing in the snapshots written before creation
+    // times were kept in the index
+    #[serde(default)]
+    pub created: Option<Vec<u64>>,
+    // the retained versions of each key, or `None` if only the latest one is retained
+    #[serde(default)]
+    pub history: Option<Vec<(String, Vec<Version>)>>,
+}
+
+impl IndexSnapshot {
+    /// Loads the snapshot in the given directory.
+    ///
+    /// Returns `None` if the directory has no snapshot.
+    pub fn load(vfs: &dyn Vfs, dir: &Path) -> Result<Option<IndexSnapshot>> {
+        match vfs.open(&snapshot_path(dir)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically replaces the snapshot in the given directory.
+    pub fn store(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(SNAPSHOT_TMP_FILE);
+        let mut writer = BufWriter::new(vfs.create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        vfs.rename(&tmp_path, &snapshot_path(dir))?;
+        vfs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Removes the snapshot in the given directory, if any.
+    pub fn remove(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        match vfs.remove_file(&snapshot_path(dir)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        vfs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Returns whether the snapshot describes a prefix of the log in the given directory.
+    pub fn is_valid(&self, vfs: &dyn Vfs, dir: &Path, manifest: &Manifest) -> Result<bool> {
+        if self.compaction_gen != manifest.compaction_gen
+            || self.gens.last() != Some(&self.gen)
+            || !manifest.gens.starts_with(&self.gens)
+        {
+            return Ok(false);
+        }
+        Ok(vfs.file_size(&log_path(dir, self.gen))? >= self.pos)
+    }
+}
+
+fn snapshot_path(dir: &Path) -> PathBuf {
+    dir.join(SNAPSHOT_FILE)
+}
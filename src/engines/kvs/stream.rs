@@ -0,0 +1,170 @@
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::{KvsError, Result, UserMeta, ValueStream, Vfs};
+
+// the number of escaped bytes of a value read at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the chunks of a value to a staging file, escaped as the contents of a JSON
+/// string.
+///
+/// A character may be split across chunks, but the whole value must be valid UTF-8.
+pub(super) fn stage_value(vfs: &dyn Vfs, path: &Path, chunks: ValueStream) -> Result<()> {
+    let mut writer = BufWriter::new(vfs.create(path)?);
+    // the bytes of a character which continues in the next chunk
+    let mut pending = Vec::new();
+    while let Ok(chunk) = smol::block_on(chunks.recv()) {
+        let mut bytes = mem::take(&mut pending);
+        bytes.extend(chunk?);
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) if e.utf8_error().error_len().is_none() => {
+                let valid = e.utf8_error().valid_up_to();
+                let mut bytes = e.into_bytes();
+                pending = bytes.split_off(valid);
+                String::from_utf8(bytes)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let quoted = serde_json::to_vec(&text)?;
+        writer.write_all(&quoted[1..quoted.len() - 1])?;
+    }
+    if !pending.is_empty() {
+        String::from_utf8(pending)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a "set" record of a key with a value staged by `stage_value`.
+///
+/// The record is laid out the way `serde_json` serializes a `Command::Set` with the
+/// given user metadata.
+pub(super) fn write_staged_record(
+    writer: &mut impl Write,
+    key: &str,
+    staged: &mut impl Read,
+    version: u64,
+    time: u64,
+    created: u64,
+    meta: &UserMeta,
+) -> Result<()> {
+    writer.write_all(&value_prefix(key)?)?;
+    io::copy(staged, writer)?;
+    write!(
+        writer,
+        r#"","version":{},"time":{},"created":{}"#,
+        version, time, created
+    )?;
+    if meta.flags != 0 {
+        write!(writer, r#","flags":{}"#, meta.flags)?;
+    }
+    if let Some(content_type) = &meta.content_type {
+        write!(
+            writer,
+            r#","content_type":{}"#,
+            serde_json::to_string(content_type)?
+        )?;
+    }
+    writer.write_all(b"}}")?;
+    Ok(())
+}
+
+/// Reads the value of the "set" record of a key in chunks and passes them to `f`.
+///
+/// Chunks are never split inside a character, so each of them is valid UTF-8.
+pub(super) fn read_value<F>(record: &mut dyn Read, key: &str, mut f: F) -> Result<()>
+where
+    F: FnMut(Vec<u8>) -> Result<()>,
+{
+    let prefix = value_prefix(key)?;
+    let mut start = vec![0; prefix.len()];
+    record.read_exact(&mut start)?;
+    if start != prefix {
+        return Err(KvsError::UnexpectedCommandType);
+    }
+
+    let mut escaped = Vec::with_capacity(CHUNK_SIZE);
+    loop {
+        let n = (&mut *record)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut escaped)?;
+        let (end, cut) = scan_escaped(&escaped);
+        if let Some(end) = end {
+            if end > 0 {
+                f(unescape(&escaped[..end])?)?;
+            }
+            return Ok(());
+        }
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if cut > 0 {
+            f(unescape(&escaped[..cut])?)?;
+            escaped.drain(..cut);
+        }
+    }
+}
+
+/// Returns the path of the staging file with the given number.
+pub(super) fn stage_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.value.tmp", id))
+}
+
+/// Returns the start of a "set" record of a key, up to the opening quote of its value.
+fn value_prefix(key: &str) -> Result<Vec<u8>> {
+    let mut prefix = br#"{"Set":{"key":"#.to_vec();
+    serde_json::to_writer(&mut prefix, key)?;
+    prefix.extend_from_slice(br#","value":""#);
+    Ok(prefix)
+}
+
+/// Scans the escaped contents of a JSON string.
+///
+/// Returns the offset of the closing quote, if any, and the length of the longest
+/// prefix which can be unescaped on its own.
+fn scan_escaped(bytes: &[u8]) -> (Option<usize>, usize) {
+    let mut i = 0;
+    let mut cut = 0;
+    while i < bytes.len() {
+        let len = match bytes[i] {
+            b'"' => return (Some(i), i),
+            b'\\' => escape_len(&bytes[i..]),
+            _ => Some(1),
+        };
+        match len {
+            Some(len) if i + len <= bytes.len() => i += len,
+            _ => break,
+        }
+        // a character is complete once the next one starts
+        if matches!(bytes.get(i), Some(b) if b & 0xC0 != 0x80) {
+            cut = i;
+        }
+    }
+    (None, cut)
+}
+
+/// Returns the length of the escape sequence at the start of the given bytes, or `None`
+/// if too few of its bytes are given to tell.
+fn escape_len(bytes: &[u8]) -> Option<usize> {
+    if *bytes.get(1)? != b'u' {
+        return Some(2);
+    }
+    let hex = std::str::from_utf8(bytes.get(2..6)?).ok();
+    match hex.and_then(|hex| u16::from_str_radix(hex, 16).ok()) {
+        // a high surrogate is followed by the escaped low one
+        Some(0xD800..=0xDBFF) => Some(12),
+        _ => Some(6),
+    }
+}
+
+/// Unescapes the contents of a JSON string.
+fn unescape(escaped: &[u8]) -> Result<Vec<u8>> {
+    let mut quoted = Vec::with_capacity(escaped.len() + 2);
+    quoted.push(b'"');
+    quoted.extend_from_slice(escaped);
+    quoted.push(b'"');
+    Ok(serde_json::from_slice::<String>(&quoted)?.into_bytes())
+}
@@ -0,0 +1,837 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use async_trait::async_trait;
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+use smol::channel::bounded;
+
+use self::manifest::Manifest;
+use self::merge::{MergeIter, Source};
+use self::sstable::{table_path, Table, TableBuilder, TableMeta};
+use self::wal::{wal_path, Wal};
+use super::{EngineStats, KvsEngine, UserMeta, ValueMeta};
+use crate::{KvsError, OsVfs, Result, ThreadPool, Vfs};
+
+mod bloom;
+mod manifest;
+mod merge;
+mod sstable;
+mod wal;
+
+const NUM_LEVELS: usize = 7;
+const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+const DEFAULT_BLOOM_BITS_PER_KEY: u32 = 10;
+const DEFAULT_LEVEL0_TABLE_LIMIT: usize = 4;
+const DEFAULT_TABLE_SIZE: u64 = 2 * 1024 * 1024;
+const DEFAULT_LEVEL_SIZE_BASE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Options to configure a `LsmStore`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// The number of bytes of keys and values in the memtable after which it is written
+    /// to a level 0 table and a new write-ahead log is started.
+    pub memtable_size: u64,
+
+    /// The size in bytes after which a data block of a table is closed. A block is
+    /// the unit of reads from a table.
+    pub block_size: usize,
+
+    /// The number of bits per key of the bloom filter of each table. 10 bits give
+    /// about 1% of false positives.
+    pub bloom_bits_per_key: u32,
+
+    /// The number of level 0 tables above which they are merged into level 1.
+    pub level0_table_limit: usize,
+
+    /// The size in bytes after which a compaction starts a new output table.
+    pub table_size: u64,
+
+    /// The maximum total size in bytes of the tables of level 1. Each deeper level may
+    /// hold `level_size_multiplier` times more than the level above it. A table of
+    /// a level above its size is merged into the next level.
+    pub level_size_base: u64,
+
+    /// The ratio of the maximum sizes of two adjacent levels.
+    pub level_size_multiplier: u64,
+
+    /// Whether every write is synced to disk before it is acknowledged. Otherwise
+    /// a write survives a crash only after the next `flush` or memtable flush.
+    pub sync_writes: bool,
+
+    /// The filesystem holding the data, which is the one of the operating system
+    /// by default.
+    pub vfs: Arc<dyn Vfs>,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            level0_table_limit: DEFAULT_LEVEL0_TABLE_LIMIT,
+            table_size: DEFAULT_TABLE_SIZE,
+            level_size_base: DEFAULT_LEVEL_SIZE_BASE,
+            level_size_multiplier: DEFAULT_LEVEL_SIZE_MULTIPLIER,
+            sync_writes: false,
+            vfs: Arc::new(OsVfs),
+        }
+    }
+}
+
+/// The `LsmStore` stores string key/value pairs in a log-structured merge tree.
+///
+/// Writes are appended to a write-ahead log and inserted into a sorted memtable.
+/// A full memtable is written to a level 0 table. Tables are sorted string tables of
+/// blocks, each with a block index and a bloom filter, named after increasing numbers
+/// with an `sst` extension name. Level 0 tables may overlap each other, while the tables
+/// of each deeper level have disjoint key ranges. Levels above their size are merged
+/// into the next level by leveled compactions, which run on the writer. The live tables
+/// and log are recorded in a `MANIFEST` file.
+///
+/// Only the memtable, block indexes and bloom filters are held in memory, so the keys
+/// do not need to fit in memory.
+///
+/// ```rust
+/// # use kvs::{LsmStore, Result, RayonThreadPool};
+/// # async fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store: LsmStore<RayonThreadPool> = LsmStore::open(current_dir()?, 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).await?;
+/// let val = store.get("key".to_owned()).await?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmStore<P: ThreadPool> {
+    // the data visible to reads, replaced by the writer
+    state: Arc<Mutex<Arc<LsmState>>>,
+    writer: Arc<Mutex<LsmWriter>>,
+    // statistics published by the writer
+    stats: Arc<Mutex<EngineStats>>,
+    thread_pool: P,
+}
+
+#[async_trait]
+impl<P: ThreadPool> KvsEngine for LsmStore<P> {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log or a table.
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().set(key, value, UserMeta::default());
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        let state = self.state.lock().unwrap().clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = lookup(&state, &key).map(|entry| entry.map(|entry| entry.value));
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Sets the value of a string key to a string with user metadata attached.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log or a table.
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().set(key, value, meta);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Gets the string value of a given string key with its metadata.
+    ///
+    /// Returns `None` if the given key does not exist.
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        let state = self.state.lock().unwrap().clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res =
+                lookup(&state, &key).map(|entry| entry.map(|entry| (entry.value, entry.meta)));
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log or a table.
+    async fn remove(&self, key: String) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().remove(key);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Atomically adds `delta` to the integer value of a given key.
+    ///
+    /// The user metadata of the key is kept.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the existing value is not an integer.
+    ///
+    /// It propagates I/O or serialization errors during writing the log or a table.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().incr(key, delta);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Atomically appends a string to the value of a given key.
+    ///
+    /// The user metadata of the key is kept.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log or a table.
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().append(key, value);
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Returns statistics about the keys and the tables. A table is reported as
+    /// a generation.
+    ///
+    /// Counting the live keys merges the memtable and all the tables, so it reads
+    /// the whole data.
+    async fn stats(&self) -> Result<EngineStats> {
+        let state = self.state.lock().unwrap().clone();
+        let stats = self.stats.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = (move || {
+                let mut stats = stats.lock().unwrap().clone();
+                for record in MergeIter::new(state.sources())? {
+                    if let (key, Some(entry)) = record? {
+                        stats.keys += 1;
+                        stats.live_bytes += (key.len() + entry.value.len()) as u64;
+                    }
+                }
+                for table in state.levels.iter().flatten() {
+                    stats.generations += 1;
+                    stats.disk_size += table.meta.size;
+                }
+                Ok(stats)
+            })();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Writes the memtable to a table and merges all the tables into a single level,
+    /// dropping the overwritten values and the removals.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the compaction.
+    async fn compact(&self) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().compact_all();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    /// Syncs the write-ahead log to disk.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the sync.
+    async fn flush(&self) -> Result<()> {
+        let writer = self.writer.clone();
+        let (tx, rx) = bounded(1);
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().wal.sync();
+
+            smol::block_on(async {
+                if !tx.is_closed() && tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+}
+
+impl<P: ThreadPool> LsmStore<P> {
+    /// Opens a `LsmStore` with the given path and the default options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during loading the tables or
+    /// replaying the write-ahead log.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_with(path, concurrency, LsmOptions::default())
+    }
+
+    /// Opens a `LsmStore` with the given path and options.
+    ///
+    /// See `open` for details.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: LsmOptions,
+    ) -> Result<Self> {
+        let dir = path.into();
+        let vfs = options.vfs.clone();
+        vfs.create_dir_all(&dir)?;
+
+        let manifest = match Manifest::load(&*vfs, &dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::default();
+                manifest.store(&*vfs, &dir)?;
+                manifest
+            }
+        };
+        remove_orphan_files(&*vfs, &dir, &manifest)?;
+
+        let mut tables = HashMap::new();
+        for meta in manifest.levels.iter().flatten() {
+            let table = Table::open(&*vfs, &dir, meta.clone())?;
+            tables.insert(meta.id, Arc::new(table));
+        }
+
+        let memtable = Memtable::new();
+        let mut memtable_size = 0;
+        let wal_path = wal_path(&dir, manifest.wal);
+        for (key, entry) in Wal::replay(&*vfs, &wal_path)? {
+            memtable_size += record_size(&key, &entry);
+            memtable.insert(key, entry);
+        }
+        let wal = Wal::open(&*vfs, &wal_path, options.sync_writes)?;
+        // A new log only survives a crash once its directory entry is synced.
+        vfs.sync_dir(&dir)?;
+
+        let state = Arc::new(Mutex::new(Arc::new(LsmState {
+            memtable: Arc::new(Memtable::new()),
+            levels: Vec::new(),
+        })));
+        let stats = Arc::new(Mutex::new(EngineStats::default()));
+        let mut writer = LsmWriter {
+            dir,
+            options,
+            manifest,
+            wal,
+            memtable_size,
+            tables,
+            compaction_cursors: vec![String::new(); NUM_LEVELS],
+            state: state.clone(),
+            stats: stats.clone(),
+        };
+        writer.publish(Arc::new(memtable));
+
+        Ok(LsmStore {
+            state,
+            writer: Arc::new(Mutex::new(writer)),
+            stats,
+            thread_pool: P::new(concurrency)?,
+        })
+    }
+}
+
+/// A value with its metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    value: String,
+    meta: ValueMeta,
+}
+
+/// A key with its value, or `None` if the key is removed.
+type Record = (String, Option<Entry>);
+
+type Memtable = SkipMap<String, Option<Entry>>;
+
+/// The data visible to reads.
+///
+/// The writer inserts into the memtable in place, and publishes a new state whenever
+/// the tables change.
+struct LsmState {
+    memtable: Arc<Memtable>,
+    // the open tables of each level, in the order of the manifest
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl LsmState {
+    /// Returns the sources of all the records from the newest to the oldest.
+    fn sources(&self) -> Vec<Source<'_>> {
+        let memtable = self
+            .memtable
+            .iter()
+            .map(|entry| Ok((entry.key().clone(), entry.value().clone())));
+        let mut sources: Vec<Source<'_>> = vec![Box::new(memtable)];
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.records()));
+        }
+        for level in &self.levels[1..] {
+            sources.push(Box::new(level.iter().flat_map(|table| table.records())));
+        }
+        sources
+    }
+}
+
+/// Looks up the entry of a key from the newest data to the oldest.
+fn lookup(state: &LsmState, key: &str) -> Result<Option<Entry>> {
+    if let Some(entry) = state.memtable.get(key) {
+        return Ok(entry.value().clone());
+    }
+    for table in &state.levels[0] {
+        if let Some(entry) = table.get(key)? {
+            return Ok(entry);
+        }
+    }
+    for level in &state.levels[1..] {
+        // The tables of a level have disjoint key ranges, so at most one may hold the key.
+        let i = level.partition_point(|table| table.meta.largest.as_str() < key);
+        if let Some(table) = level
+            .get(i)
+            .filter(|table| table.meta.smallest.as_str() <= key)
+        {
+            if let Some(entry) = table.get(key)? {
+                return Ok(entry);
+            }
+        }
+    }
+    Ok(None)
+}
+
+struct LsmWriter {
+    dir: PathBuf,
+    options: LsmOptions,
+    manifest: Manifest,
+    wal: Wal,
+    // the number of bytes of keys and values written to the memtable
+    memtable_size: u64,
+    // the open tables by number
+    tables: HashMap<u64, Arc<Table>>,
+    // the largest key compacted by the latest compaction of each level
+    compaction_cursors: Vec<String>,
+    state: Arc<Mutex<Arc<LsmState>>>,
+    stats: Arc<Mutex<EngineStats>>,
+}
+
+impl LsmWriter {
+    fn set(&mut self, key: String, value: String, user: UserMeta) -> Result<()> {
+        let now = SystemTime::now();
+        let created = self.get(&key)?.map_or(now, |entry| entry.meta.created);
+        let meta = ValueMeta {
+            created,
+            updated: now,
+            user,
+        };
+        self.write(key, Some(Entry { value, meta }))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write(key, None)
+    }
+
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let (value, user) = match self.get(&key)? {
+            Some(entry) => {
+                let value = entry
+                    .value
+                    .parse::<i64>()
+                    .map_err(|_| KvsError::NotAnInteger)?;
+                (value, entry.meta.user)
+            }
+            None => (0, UserMeta::default()),
+        };
+        let value = value.checked_add(delta).ok_or(KvsError::IntegerOverflow)?;
+        self.set(key, value.to_string(), user)?;
+        Ok(value)
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<String> {
+        let (value, user) = match self.get(&key)? {
+            Some(entry) => (entry.value + &value, entry.meta.user),
+            None => (value, UserMeta::default()),
+        };
+        self.set(key, value.clone(), user)?;
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Entry>> {
+        lookup(&self.current(), key)
+    }
+
+    fn current(&self) -> Arc<LsmState> {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Logs the record of a key and inserts it into the memtable, which is flushed once
+    /// it is full.
+    fn write(&mut self, key: String, entry: Option<Entry>) -> Result<()> {
+        self.wal.append(&key, &entry)?;
+        self.memtable_size += record_size(&key, &entry);
+        self.current().memtable.insert(key, entry);
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable to a new level 0 table and starts a new log, then compacts
+    /// the levels above their limits.
+    fn flush_memtable(&mut self) -> Result<()> {
+        let memtable = self.current().memtable.clone();
+        if memtable.is_empty() {
+            return Ok(());
+        }
+        let mut manifest = self.manifest.clone();
+        let mut builder = self.new_table(&mut manifest)?;
+        for entry in memtable.iter() {
+            builder.add(entry.key(), entry.value())?;
+        }
+        let meta = builder.finish()?;
+        self.open_tables(slice::from_ref(&meta))?;
+        manifest.levels[0].insert(0, meta);
+
+        let old_wal = manifest.wal;
+        manifest.wal = manifest.next_id();
+        let wal = Wal::open(
+            &*self.options.vfs,
+            &wal_path(&self.dir, manifest.wal),
+            self.options.sync_writes,
+        )?;
+        manifest.store(&*self.options.vfs, &self.dir)?;
+        self.manifest = manifest;
+        self.wal = wal;
+        self.memtable_size = 0;
+        self.publish(Arc::new(Memtable::new()));
+        self.remove_file(&wal_path(&self.dir, old_wal));
+
+        self.maybe_compact()
+    }
+
+    /// Compacts the levels above their limits, from the top down.
+    fn maybe_compact(&mut self) -> Result<()> {
+        loop {
+            let levels = &self.manifest.levels;
+            let level = if levels[0].len() > self.options.level0_table_limit {
+                0
+            } else {
+                let oversized = (1..NUM_LEVELS - 1).find(|&level| {
+                    levels[level].iter().map(|table| table.size).sum::<u64>()
+                        > self.max_level_size(level)
+                });
+                match oversized {
+                    Some(level) => level,
+                    None => return Ok(()),
+                }
+            };
+            self.compact_level(level)?;
+        }
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        let multiplier = self
+            .options
+            .level_size_multiplier
+            .saturating_pow(level as u32 - 1);
+        self.options.level_size_base.saturating_mul(multiplier)
+    }
+
+    /// Merges tables of a level into the overlapping tables of the next level.
+    ///
+    /// All the tables of level 0 are merged since they overlap each other. A single
+    /// table is picked from the other levels, the one after the key range of the latest
+    /// compaction of the level, so that compactions cycle through the keys.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let tables = &self.manifest.levels[level];
+        let inputs = if level == 0 {
+            tables.clone()
+        } else {
+            let cursor = &self.compaction_cursors[level];
+            let table = tables
+                .iter()
+                .find(|table| table.smallest > *cursor)
+                .unwrap_or(&tables[0]);
+            vec![table.clone()]
+        };
+        let smallest = inputs.iter().map(|table| &table.smallest).min().unwrap();
+        let largest = inputs.iter().map(|table| &table.largest).max().unwrap();
+        let overlapping = self.manifest.levels[level + 1]
+            .iter()
+            .filter(|table| table.largest >= *smallest && table.smallest <= *largest)
+            .map(|table| table.id)
+            .collect();
+        self.compaction_cursors[level] = largest.clone();
+
+        let mut sources: Vec<Vec<u64>> = if level == 0 {
+            inputs.iter().map(|table| vec![table.id]).collect()
+        } else {
+            vec![inputs.iter().map(|table| table.id).collect()]
+        };
+        sources.push(overlapping);
+        self.merge_tables(sources, level + 1)
+    }
+
+    /// Writes the memtable to a table and merges all the tables into the deepest level
+    /// holding any, or level 1.
+    fn compact_all(&mut self) -> Result<()> {
+        self.flush_memtable()?;
+        let levels = &self.manifest.levels;
+        let output_level = (1..NUM_LEVELS)
+            .rev()
+            .find(|&level| !levels[level].is_empty())
+            .unwrap_or(1);
+        let mut sources: Vec<Vec<u64>> = levels[0].iter().map(|table| vec![table.id]).collect();
+        for level in &levels[1..] {
+            sources.push(level.iter().map(|table| table.id).collect());
+        }
+        if sources.iter().all(Vec::is_empty) {
+            return Ok(());
+        }
+        self.merge_tables(sources, output_level)?;
+        self.maybe_compact()
+    }
+
+    /// Merges the given tables into new tables of the output level, replacing them.
+    ///
+    /// The sources are ordered from the newest to the oldest, and the tables of each
+    /// source have disjoint key ranges in ascending order. Removals are dropped once
+    /// no level below the output may hold an older value of the key.
+    fn merge_tables(&mut self, sources: Vec<Vec<u64>>, output_level: usize) -> Result<()> {
+        let start = Instant::now();
+        let drop_removals = self.manifest.levels[output_level + 1..]
+            .iter()
+            .all(Vec::is_empty);
+        let inputs: Vec<Vec<Arc<Table>>> = sources
+            .iter()
+            .map(|ids| ids.iter().map(|id| self.tables[id].clone()).collect())
+            .collect();
+        let records = MergeIter::new(
+            inputs
+                .iter()
+                .map(|tables| {
+                    Box::new(tables.iter().flat_map(|table| table.records())) as Source<'_>
+                })
+                .collect(),
+        )?;
+
+        let mut manifest = self.manifest.clone();
+        let mut outputs = Vec::new();
+        let mut builder = None;
+        for record in records {
+            let (key, entry) = record?;
+            if entry.is_none() && drop_removals {
+                continue;
+            }
+            if builder.is_none() {
+                builder = Some(self.new_table(&mut manifest)?);
+            }
+            let table = builder.as_mut().unwrap();
+            table.add(&key, &entry)?;
+            if table.size() >= self.options.table_size {
+                outputs.push(builder.take().unwrap().finish()?);
+            }
+        }
+        if let Some(table) = builder {
+            outputs.push(table.finish()?);
+        }
+        self.open_tables(&outputs)?;
+
+        let stale: HashSet<u64> = sources.into_iter().flatten().collect();
+        for level in &mut manifest.levels {
+            level.retain(|table| !stale.contains(&table.id));
+        }
+        let level = &mut manifest.levels[output_level];
+        level.extend(outputs);
+        level.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        manifest.store(&*self.options.vfs, &self.dir)?;
+        self.manifest = manifest;
+        for id in &stale {
+            self.tables.remove(id);
+        }
+        self.publish(self.current().memtable.clone());
+        // Reads of the stale tables keep their memory maps.
+        for &id in &stale {
+            self.remove_file(&table_path(&self.dir, id));
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.compactions += 1;
+        stats.compaction_time += start.elapsed();
+        Ok(())
+    }
+
+    fn new_table(&self, manifest: &mut Manifest) -> Result<TableBuilder> {
+        TableBuilder::new(
+            &*self.options.vfs,
+            &self.dir,
+            manifest.next_id(),
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        )
+    }
+
+    fn open_tables(&mut self, metas: &[TableMeta]) -> Result<()> {
+        for meta in metas {
+            let table = Table::open(&*self.options.vfs, &self.dir, meta.clone())?;
+            self.tables.insert(meta.id, Arc::new(table));
+        }
+        Ok(())
+    }
+
+    /// Publishes the given memtable with the tables of the manifest to reads.
+    fn publish(&mut self, memtable: Arc<Memtable>) {
+        let levels = self
+            .manifest
+            .levels
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .map(|table| self.tables[&table.id].clone())
+                    .collect()
+            })
+            .collect();
+        *self.state.lock().unwrap() = Arc::new(LsmState { memtable, levels });
+    }
+
+    /// Removes a file which is no longer live.
+    ///
+    /// Failing to delete it is not fatal because it is removed at the next open.
+    fn remove_file(&self, path: &Path) {
+        if let Err(e) = self.options.vfs.remove_file(path) {
+            error!("{:?} cannot be deleted: {}", path, e);
+        }
+    }
+}
+
+fn record_size(key: &str, entry: &Option<Entry>) -> u64 {
+    (key.len() + entry.as_ref().map_or(0, |entry| entry.value.len())) as u64
+}
+
+/// Removes table and log files which are not listed in the manifest and the temporary
+/// file of the manifest.
+///
+/// Failing to delete an orphan file is not fatal because it is never read.
+fn remove_orphan_files(vfs: &dyn Vfs, dir: &Path, manifest: &Manifest) -> Result<()> {
+    for path in vfs.read_dir(dir)? {
+        let orphan = match path.extension().and_then(OsStr::to_str) {
+            Some("sst") | Some("wal") => {
+                let id = path.file_stem().and_then(OsStr::to_str).map(str::parse);
+                matches!(id, Some(Ok(id)) if !manifest.is_live(id))
+            }
+            Some("tmp") => true,
+            _ => false,
+        };
+        if orphan {
+            warn!("Removing orphan file {:?}", path);
+            if let Err(e) = vfs.remove_file(&path) {
+                error!("{:?} cannot be deleted: {}", path, e);
+            }
+        }
+    }
+    Ok(())
+}
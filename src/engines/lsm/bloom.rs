@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+// the number of hash functions is capped to bound the cost of a lookup
+const MAX_HASHES: u32 = 30;
+
+/// A bloom filter of the keys of a table.
+///
+/// It answers whether a key may be in the table without reading any block, with false
+/// positives but no false negatives.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter of the keys with the given hashes, spending about `bits_per_key`
+    /// bits per key.
+    pub fn build(key_hashes: &[u64], bits_per_key: u32) -> Self {
+        let words = key_hashes.len() * bits_per_key as usize / 64 + 1;
+        // k = ln(2) * bits per key minimizes the false positive rate
+        let hashes = ((f64::from(bits_per_key) * 0.69) as u32).clamp(1, MAX_HASHES);
+        let mut filter = BloomFilter {
+            bits: vec![0; words],
+            hashes,
+        };
+        for &hash in key_hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// Returns whether the key may have been added to the filter.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key_hash(key))
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Derives the positions of the bits of a key from its hash by double hashing.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let n_bits = self.bits.len() as u64 * 64;
+        let delta = hash.rotate_right(17) | 1;
+        (0..u64::from(self.hashes)).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % n_bits)
+    }
+}
+
+/// Returns the FNV-1a hash of a key, which is stable across builds unlike the hasher
+/// of the standard library.
+pub(super) fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
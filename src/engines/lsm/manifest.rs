@@ -0,0 +1,82 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::sstable::TableMeta;
+use super::NUM_LEVELS;
+use crate::{Result, Vfs};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+
+/// The `Manifest` records the tables of each level and the active write-ahead log.
+///
+/// It is the source of truth of `LsmStore::open`. Table and log files which are not
+/// listed in it, e.g. the output of an unfinished compaction, are removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    // the number of the next table or log file
+    pub next_id: u64,
+    // the number of the active log
+    pub wal: u64,
+    // the tables of level 0 from the newest to the oldest, and the tables of the other
+    // levels in ascending order of keys
+    pub levels: Vec<Vec<TableMeta>>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            next_id: 1,
+            wal: 0,
+            levels: vec![Vec::new(); NUM_LEVELS],
+        }
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest in the given directory.
+    ///
+    /// Returns `None` if the directory has no manifest.
+    pub fn load(vfs: &dyn Vfs, dir: &Path) -> Result<Option<Manifest>> {
+        let mut file = match vfs.open(&manifest_path(dir)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Atomically replaces the manifest in the given directory.
+    ///
+    /// The manifest is written to a temporary file which is synced and then renamed
+    /// over the old one, so a crash leaves either the old or the new manifest.
+    pub fn store(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_TMP_FILE);
+        let mut file = vfs.create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        vfs.rename(&tmp_path, &manifest_path(dir))?;
+        vfs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Allocates the number of a new table or log file.
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Returns whether a table or log file is live.
+    pub fn is_live(&self, id: u64) -> bool {
+        id == self.wal || self.levels.iter().flatten().any(|table| table.id == id)
+    }
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE)
+}
@@ -0,0 +1,57 @@
+use super::Record;
+use crate::Result;
+
+/// A source of records in ascending order of keys.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Record>> + 'a>;
+
+/// Merges sources of records into one in ascending order of keys.
+///
+/// The sources are ordered from the newest to the oldest, and only the record of the
+/// newest source holding a key is kept.
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    // the next record of each source
+    heads: Vec<Option<Record>>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: Vec<Source<'a>>) -> Result<Self> {
+        let mut iter = MergeIter {
+            heads: vec![None; sources.len()],
+            sources,
+        };
+        for i in 0..iter.sources.len() {
+            iter.advance(i)?;
+        }
+        Ok(iter)
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        // `min_by` returns the first of equal keys, which is the newest.
+        let newest = (0..self.heads.len())
+            .filter(|&i| self.heads[i].is_some())
+            .min_by(|&a, &b| {
+                let key = |i: usize| self.heads[i].as_ref().map(|(key, _)| key);
+                key(a).cmp(&key(b))
+            })?;
+        let (key, entry) = self.heads[newest].take()?;
+        for i in 0..self.heads.len() {
+            let shadowed = matches!(&self.heads[i], Some((other, _)) if *other == key);
+            if i == newest || shadowed {
+                if let Err(e) = self.advance(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(Ok((key, entry)))
+    }
+}
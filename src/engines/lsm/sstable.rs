@@ -0,0 +1,246 @@
+use std::convert::TryInto;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::bloom::{key_hash, BloomFilter};
+use super::{Entry, Record};
+use crate::vfs::MappedFile;
+use crate::{KvsError, Result, Vfs, VfsFile};
+
+// ends every table, so that a table cut short is detected
+const MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+// the offsets and lengths of the index and the filter, then the magic number
+const FOOTER_SIZE: usize = 5 * 8;
+
+/// The metadata of a table recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct TableMeta {
+    pub id: u64,
+    pub size: u64,
+    pub smallest: String,
+    pub largest: String,
+}
+
+/// The location of a data block in a table.
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    // the largest key of the block
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// An immutable sorted string table.
+///
+/// A table is a sequence of data blocks holding records in ascending order of keys,
+/// followed by the index of the blocks, a bloom filter of the keys and a footer locating
+/// both of them. The index and the filter are held in memory, while the blocks are read
+/// through a memory map.
+pub(super) struct Table {
+    pub meta: TableMeta,
+    data: MappedFile,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+}
+
+impl Table {
+    /// Opens the table described by the given metadata.
+    pub fn open(vfs: &dyn Vfs, dir: &Path, meta: TableMeta) -> Result<Table> {
+        // Table files are never modified once they are listed in the manifest.
+        let data = vfs.map(&table_path(dir, meta.id))?;
+        let bytes = (*data).as_ref();
+        if bytes.len() < FOOTER_SIZE {
+            return Err(corrupt_table(meta.id));
+        }
+        let footer = &bytes[bytes.len() - FOOTER_SIZE..];
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..(i + 1) * 8].try_into().unwrap());
+        if field(4) != MAGIC {
+            return Err(corrupt_table(meta.id));
+        }
+        let index = serde_json::from_slice(section(bytes, field(0), field(1), meta.id)?)?;
+        let filter = serde_json::from_slice(section(bytes, field(2), field(3), meta.id)?)?;
+        Ok(Table {
+            meta,
+            data,
+            index,
+            filter,
+        })
+    }
+
+    /// Looks up the record of a key.
+    ///
+    /// Returns `None` if the table holds no record of the key, and `Some(None)` if it
+    /// holds a removal.
+    pub fn get(&self, key: &str) -> Result<Option<Option<Entry>>> {
+        if !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        let handle = match self.index.get(block) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        for record in Deserializer::from_slice(self.block(handle)?).into_iter::<Record>() {
+            let (record_key, entry) = record?;
+            if record_key == key {
+                return Ok(Some(entry));
+            }
+            if record_key.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns all the records of the table in ascending order of keys.
+    pub fn records(&self) -> impl Iterator<Item = Result<Record>> + '_ {
+        self.index.iter().flat_map(move |handle| {
+            let (records, err) = match self.block(handle) {
+                Ok(block) => (
+                    Some(Deserializer::from_slice(block).into_iter::<Record>()),
+                    None,
+                ),
+                Err(e) => (None, Some(Err(e))),
+            };
+            records
+                .into_iter()
+                .flatten()
+                .map(|record| record.map_err(KvsError::from))
+                .chain(err)
+        })
+    }
+
+    fn block(&self, handle: &BlockHandle) -> Result<&[u8]> {
+        section(
+            (*self.data).as_ref(),
+            handle.offset,
+            handle.len,
+            self.meta.id,
+        )
+    }
+}
+
+/// Writes a new table.
+pub(super) struct TableBuilder {
+    id: u64,
+    writer: BufWriter<Box<dyn VfsFile>>,
+    // the number of bytes of the blocks written so far
+    offset: u64,
+    block: Vec<u8>,
+    block_size: usize,
+    bits_per_key: u32,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+    smallest: Option<String>,
+    last_key: String,
+}
+
+impl TableBuilder {
+    /// Creates the file of a new table.
+    pub fn new(
+        vfs: &dyn Vfs,
+        dir: &Path,
+        id: u64,
+        block_size: usize,
+        bits_per_key: u32,
+    ) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            id,
+            writer: BufWriter::new(vfs.create(&table_path(dir, id))?),
+            offset: 0,
+            block: Vec::with_capacity(block_size),
+            block_size,
+            bits_per_key,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+            smallest: None,
+            last_key: String::new(),
+        })
+    }
+
+    /// Adds the record of a key, which must be greater than the keys added before.
+    pub fn add(&mut self, key: &str, entry: &Option<Entry>) -> Result<()> {
+        serde_json::to_writer(&mut self.block, &(key, entry))?;
+        self.key_hashes.push(key_hash(key));
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_owned());
+        }
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes of the records added so far.
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Writes the index, the filter and the footer, and syncs the table.
+    pub fn finish(mut self) -> Result<TableMeta> {
+        self.finish_block()?;
+        let index = serde_json::to_vec(&self.index)?;
+        let filter = serde_json::to_vec(&BloomFilter::build(&self.key_hashes, self.bits_per_key))?;
+        let index_offset = self.offset;
+        let filter_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&filter)?;
+        let footer = [
+            index_offset,
+            index.len() as u64,
+            filter_offset,
+            filter.len() as u64,
+            MAGIC,
+        ];
+        for field in &footer {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(TableMeta {
+            id: self.id,
+            size: filter_offset + filter.len() as u64 + FOOTER_SIZE as u64,
+            smallest: self.smallest.unwrap_or_default(),
+            largest: self.last_key,
+        })
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Returns the given range of the bytes of a table, checking that it is in bounds.
+fn section(bytes: &[u8], offset: u64, len: u64, id: u64) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .filter(|&end| end <= bytes.len() as u64)
+        .map(|end| &bytes[offset as usize..end as usize])
+        .ok_or_else(|| corrupt_table(id))
+}
+
+fn corrupt_table(id: u64) -> KvsError {
+    KvsError::StringError(format!("Table {} is corrupt", id))
+}
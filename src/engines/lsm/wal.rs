@@ -0,0 +1,91 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Deserializer;
+
+use super::{Entry, Record};
+use crate::{Result, Vfs, VfsFile};
+
+/// The write-ahead log of the records in the memtable.
+///
+/// A new log is started whenever the memtable is written to a table, so a log only
+/// holds the records which are not in any table yet.
+pub(super) struct Wal {
+    file: Box<dyn VfsFile>,
+    // the length of the complete records
+    len: u64,
+    sync_writes: bool,
+}
+
+impl Wal {
+    /// Opens a log for appending, creating it if it does not exist.
+    pub fn open(vfs: &dyn Vfs, path: &Path, sync_writes: bool) -> Result<Wal> {
+        let file = vfs.append(path)?;
+        Ok(Wal {
+            file,
+            len: vfs.file_size(path)?,
+            sync_writes,
+        })
+    }
+
+    /// Reads the records of a log.
+    ///
+    /// A record cut short at the end of the log, which was being written during a crash,
+    /// is dropped and truncated from the log.
+    pub fn replay(vfs: &dyn Vfs, path: &Path) -> Result<Vec<Record>> {
+        let mut file = match vfs.open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut stream = Deserializer::from_slice(&bytes).into_iter::<Record>();
+        loop {
+            let offset = stream.byte_offset();
+            match stream.next() {
+                Some(Ok(record)) => records.push(record),
+                Some(Err(e)) if e.is_eof() => {
+                    warn!("Truncating a torn record at {} of {:?}", offset, path);
+                    vfs.append(path)?.set_len(offset as u64)?;
+                    break;
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(records)
+    }
+
+    /// Appends the record of a key, syncing it if every write is synced.
+    ///
+    /// A record which fails to be written is truncated, so that the following records
+    /// are not appended after a torn one.
+    pub fn append(&mut self, key: &str, entry: &Option<Entry>) -> Result<()> {
+        let record = serde_json::to_vec(&(key, entry))?;
+        let res = self.file.write_all(&record).and_then(|()| {
+            if self.sync_writes {
+                self.file.sync_all()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            self.file.set_len(self.len)?;
+            return Err(e.into());
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Syncs the log to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
@@ -0,0 +1,228 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{EngineStats, KvsEngine, UserMeta, ValueMeta};
+use crate::{KvsError, Result};
+
+/// A `KvsEngine` which holds the data in memory.
+///
+/// The data lives in an ordered concurrent map, so reads never wait. Writes are
+/// serialized by a lock, which makes read-modify-write operations atomic.
+///
+/// An engine created with `new` never touches the disk. One opened with `open` saves
+/// a snapshot of the data to a file on `flush` and when its last handle is dropped,
+/// and loads it back when it is opened again.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemKvsEngine, Result};
+/// # async fn try_main() -> Result<()> {
+/// let engine = MemKvsEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned()).await?;
+/// assert_eq!(engine.get("key".to_owned()).await?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemKvsEngine {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    map: SkipMap<String, Entry>,
+    // held by writes
+    write_lock: Mutex<()>,
+    // where the data is saved, if anywhere
+    snapshot_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    value: String,
+    meta: ValueMeta,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty `MemKvsEngine` which is never saved.
+    pub fn new() -> Self {
+        MemKvsEngine::default()
+    }
+
+    /// Opens a `MemKvsEngine` saved to the given snapshot file.
+    ///
+    /// The data of the snapshot is loaded if the file exists.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during loading the snapshot.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let map = SkipMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let entries: Vec<(String, Entry)> = serde_json::from_reader(BufReader::new(file))?;
+                for (key, entry) in entries {
+                    map.insert(key, entry);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(MemKvsEngine {
+            inner: Arc::new(Inner {
+                map,
+                write_lock: Mutex::new(()),
+                snapshot_path: Some(path),
+            }),
+        })
+    }
+
+    /// Sets a key to a value, keeping its creation time.
+    ///
+    /// The write lock must be held.
+    fn put(&self, key: String, value: String, user: UserMeta) {
+        let now = SystemTime::now();
+        let created = self
+            .inner
+            .map
+            .get(&key)
+            .map_or(now, |entry| entry.value().meta.created);
+        let meta = ValueMeta {
+            created,
+            updated: now,
+            user,
+        };
+        self.inner.map.insert(key, Entry { value, meta });
+    }
+}
+
+impl Inner {
+    /// Atomically replaces the snapshot file with the current data, if there is one.
+    fn save(&self) -> Result<()> {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut serializer = serde_json::Serializer::new(&mut writer);
+        let entries = self.map.iter();
+        serializer
+            .collect_seq(entries.map(|entry| (entry.key().clone(), entry.value().clone())))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            error!("Failed to save the memory engine snapshot: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl KvsEngine for MemKvsEngine {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        self.put(key, value, UserMeta::default());
+        Ok(())
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        self.put(key, value, meta);
+        Ok(())
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .inner
+            .map
+            .get(&key)
+            .map(|entry| entry.value().value.clone()))
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        Ok(self.inner.map.get(&key).map(|entry| {
+            let entry = entry.value();
+            (entry.value.clone(), entry.meta.clone())
+        }))
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        self.inner
+            .map
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    /// The user metadata of the key is kept.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        let (value, user) = match self.inner.map.get(&key) {
+            Some(entry) => {
+                let entry = entry.value();
+                let value = entry
+                    .value
+                    .parse::<i64>()
+                    .map_err(|_| KvsError::NotAnInteger)?;
+                (value, entry.meta.user.clone())
+            }
+            None => (0, UserMeta::default()),
+        };
+        let value = value.checked_add(delta).ok_or(KvsError::IntegerOverflow)?;
+        self.put(key, value.to_string(), user);
+        Ok(value)
+    }
+
+    /// The user metadata of the key is kept.
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        let (value, user) = match self.inner.map.get(&key) {
+            Some(entry) => {
+                let entry = entry.value();
+                (entry.value.clone() + &value, entry.meta.user.clone())
+            }
+            None => (value, UserMeta::default()),
+        };
+        self.put(key, value.clone(), user);
+        Ok(value)
+    }
+
+    /// Reports the number of keys and the bytes of the keys and values.
+    async fn stats(&self) -> Result<EngineStats> {
+        let map = &self.inner.map;
+        Ok(EngineStats {
+            keys: map.len() as u64,
+            live_bytes: map
+                .iter()
+                .map(|entry| (entry.key().len() + entry.value().value.len()) as u64)
+                .sum(),
+            ..EngineStats::default()
+        })
+    }
+
+    /// There is nothing to compact in memory.
+    async fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Saves the snapshot if the engine was opened with one.
+    async fn flush(&self) -> Result<()> {
+        let _lock = self.inner.write_lock.lock().unwrap();
+        self.inner.save()
+    }
+}
@@ -1,12 +1,30 @@
+mod boxed;
 mod kvs;
+mod lsm;
+mod memory;
+mod registry;
 mod sled;
 
-pub use self::kvs::KvStore;
+pub use self::boxed::{BoxedEngine, DynEngine};
+pub use self::kvs::{HistoryRetention, KeyVersion, KvStore, KvStoreOptions, SalvageReport};
+pub use self::lsm::{LsmOptions, LsmStore};
+pub use self::memory::MemKvsEngine;
+pub use self::registry::{EngineConfig, EngineRegistry};
 pub use self::sled::SledKvsEngine;
 
+use std::time::{Duration, SystemTime};
+
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use smol::channel::{self, Receiver};
+
+use crate::{KvsError, Layer, Result};
 
-use crate::Result;
+/// A stream of the chunks of a value.
+///
+/// The value is the concatenation of the chunks received until the channel is closed.
+/// An error received instead of a chunk aborts the value.
+pub type ValueStream = Receiver<Result<Vec<u8>>>;
 
 /// Trait for a key value storage engine.
 #[async_trait]
@@ -21,10 +39,203 @@ pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// Returns `None` if the given key does not exist.
     async fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Sets the value of a string key to a string with user metadata attached.
+    ///
+    /// `set` attaches the default metadata. The creation time of an existing key is kept.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine cannot store metadata.
+    async fn set_with_meta(&self, _key: String, _value: String, _meta: UserMeta) -> Result<()> {
+        Err(KvsError::Unsupported("value metadata"))
+    }
+
+    /// Gets the string value of a given string key with its metadata.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine cannot store metadata.
+    async fn get_with_meta(&self, _key: String) -> Result<Option<(String, ValueMeta)>> {
+        Err(KvsError::Unsupported("value metadata"))
+    }
+
+    /// Gets the string values of the given string keys.
+    ///
+    /// Returns the values in the order of the keys, with `None` for the keys which
+    /// do not exist.
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    /// Sets the value of a string key to the chunks of a stream.
+    ///
+    /// A character may be split across chunks, but the whole value must be valid UTF-8.
+    /// The value is only set if the stream ends without an error.
+    ///
+    /// The default implementation collects the whole value in memory before setting it.
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        let mut value = Vec::new();
+        while let Ok(chunk) = chunks.recv().await {
+            value.extend(chunk?);
+        }
+        self.set(key, String::from_utf8(value)?).await
+    }
+
+    /// Gets the value of a given string key as a stream of chunks.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// The default implementation reads the whole value in memory and sends it as
+    /// a single chunk.
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        let value = match self.get(key).await? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let (tx, rx) = channel::bounded(1);
+        tx.try_send(Ok(value.into_bytes()))
+            .expect("a new channel has room for a chunk");
+        Ok(Some(rx))
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     async fn remove(&self, key: String) -> Result<()>;
+
+    /// Atomically adds `delta` to the integer value of a given key.
+    ///
+    /// A non-existent key is treated as `0`. Returns the value after the addition.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the existing value is not an integer and
+    /// `KvsError::IntegerOverflow` if the addition overflows.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Atomically subtracts `delta` from the integer value of a given key.
+    ///
+    /// See `incr` for the semantics.
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KvsError::IntegerOverflow)?;
+        self.incr(key, delta).await
+    }
+
+    /// Atomically appends a string to the value of a given key.
+    ///
+    /// A non-existent key is treated as an empty string. Returns the value after the append.
+    async fn append(&self, key: String, value: String) -> Result<String>;
+
+    /// Returns statistics about the data held by the engine.
+    async fn stats(&self) -> Result<EngineStats>;
+
+    /// Compacts the whole data held by the engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine cannot be compacted manually.
+    async fn compact(&self) -> Result<()> {
+        Err(KvsError::Unsupported("manual compaction"))
+    }
+
+    /// Makes all the previous writes durable.
+    async fn flush(&self) -> Result<()>;
+
+    /// Limits the number of bytes per second read and written by compactions.
+    ///
+    /// A limit of 0 removes the limit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine has no such limit.
+    async fn set_compaction_rate_limit(&self, _bytes_per_sec: u64) -> Result<()> {
+        Err(KvsError::Unsupported("compaction rate limit"))
+    }
+
+    /// Wraps the engine with a layer.
+    ///
+    /// ```rust
+    /// # use kvs::{CacheLayer, KvsEngine, MemKvsEngine, MetricsLayer};
+    /// let engine = MemKvsEngine::new()
+    ///     .with_layer(CacheLayer::new(1024 * 1024))
+    ///     .with_layer(MetricsLayer);
+    /// ```
+    fn with_layer<L: Layer<Self>>(self, layer: L) -> L::Engine
+    where
+        Self: Sized,
+    {
+        layer.layer(self)
+    }
+}
+
+/// Statistics reported by a `KvsEngine`.
+///
+/// Fields which have no meaning for an engine are reported as zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of live keys.
+    pub keys: u64,
+    /// The number of bytes used by the live entries.
+    pub live_bytes: u64,
+    /// The number of bytes of stale entries that can be reclaimed by a compaction.
+    pub uncompacted_bytes: u64,
+    /// The number of log generations on disk.
+    pub generations: u64,
+    /// The number of compactions run since the engine was opened.
+    pub compactions: u64,
+    /// The total time spent in compactions since the engine was opened.
+    pub compaction_time: Duration,
+    /// The total size of the data files on disk.
+    pub disk_size: u64,
+    /// The number of bytes to be rewritten by the running compaction, or 0 if none is running.
+    pub compaction_total_bytes: u64,
+    /// The number of bytes rewritten so far by the running compaction.
+    pub compaction_done_bytes: u64,
+    /// Whether the engine rejects writes after a write failure.
+    pub read_only: bool,
+    /// The number of keys evicted in cache mode since the engine was opened.
+    pub evictions: u64,
+    /// The requests of each operation, if they are recorded by a `Metrics` layer.
+    pub ops: Vec<OpStats>,
+}
+
+/// The requests of an operation recorded by a `Metrics` layer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpStats {
+    /// The name of the operation, like `get`.
+    pub op: String,
+    /// The number of requests.
+    pub requests: u64,
+    /// The number of requests which failed.
+    pub errors: u64,
+    /// The total time spent in the requests.
+    pub total_time: Duration,
+}
+
+/// Metadata attached to a value by the user.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserMeta {
+    /// An opaque integer, like the flags of a memcached item.
+    pub flags: u32,
+    /// The content type of the value, if any.
+    pub content_type: Option<String>,
+}
+
+/// Metadata of a value reported by a `KvsEngine`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueMeta {
+    /// When the key was set while it did not exist.
+    pub created: SystemTime,
+    /// When the value was last written.
+    pub updated: SystemTime,
+    /// The metadata attached by the last write.
+    pub user: UserMeta,
 }
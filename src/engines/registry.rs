@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use super::{BoxedEngine, KvStore, KvStoreOptions, KvsEngine, LsmStore, MemKvsEngine};
+use super::{SalvageReport, SledKvsEngine};
+use crate::{KvsError, RayonThreadPool, Result};
+
+/// The file of the snapshot of the memory engine in its directory.
+const MEMORY_SNAPSHOT: &str = "memory.snapshot";
+
+/// The settings passed to the constructor of an engine.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// The directory holding the data of the engine.
+    pub path: PathBuf,
+    /// The number of threads serving the requests.
+    pub concurrency: u32,
+    /// Skips corrupt records instead of refusing to open, if the engine supports it.
+    pub salvage: bool,
+    /// Runs the engine as a cache holding at most the given live bytes, if the engine
+    /// supports it.
+    pub cache_capacity: Option<u64>,
+    /// Saves the data of the memory engine to a snapshot in the directory and loads it
+    /// again at the next open.
+    pub memory_snapshot: bool,
+}
+
+impl EngineConfig {
+    /// Creates a config for an engine in the given directory with the other settings
+    /// disabled.
+    pub fn new(path: impl Into<PathBuf>, concurrency: u32) -> Self {
+        EngineConfig {
+            path: path.into(),
+            concurrency,
+            salvage: false,
+            cache_capacity: None,
+            memory_snapshot: false,
+        }
+    }
+
+    /// Rejects the settings which an engine does not support.
+    fn reject_kvs_options(&self) -> Result<()> {
+        if self.salvage {
+            return Err(KvsError::Unsupported("salvage mode"));
+        }
+        if self.cache_capacity.is_some() {
+            return Err(KvsError::Unsupported("cache mode"));
+        }
+        Ok(())
+    }
+
+    /// Rejects the settings which only the memory engine supports.
+    fn reject_memory_options(&self) -> Result<()> {
+        if self.memory_snapshot {
+            return Err(KvsError::Unsupported("memory snapshot"));
+        }
+        Ok(())
+    }
+}
+
+type Constructor = Box<dyn Fn(&EngineConfig) -> Result<BoxedEngine> + Send + Sync>;
+
+/// A registry of the engines which can be opened by name.
+///
+/// `EngineRegistry::default()` holds the engines of this crate: `kvs`, `sled`, `memory`
+/// and `lsm`. Other engines are added by `register`.
+///
+/// ```rust
+/// # use kvs::{EngineConfig, EngineRegistry, MemKvsEngine};
+/// let mut registry = EngineRegistry::default();
+/// registry.register("scratch", |_| Ok(MemKvsEngine::new()));
+/// let engine = registry.open("scratch", &EngineConfig::new(".", 1))?;
+/// # kvs::Result::Ok(())
+/// ```
+pub struct EngineRegistry {
+    constructors: BTreeMap<String, Constructor>,
+}
+
+impl EngineRegistry {
+    /// Creates a registry without any engine.
+    pub fn new() -> Self {
+        EngineRegistry {
+            constructors: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the constructor of an engine under a name, replacing the engine
+    /// registered under it before, if any.
+    pub fn register<E, F>(&mut self, name: impl Into<String>, constructor: F)
+    where
+        E: KvsEngine,
+        F: Fn(&EngineConfig) -> Result<E> + Send + Sync + 'static,
+    {
+        let constructor = move |config: &EngineConfig| constructor(config).map(BoxedEngine::new);
+        self.constructors.insert(name.into(), Box::new(constructor));
+    }
+
+    /// Returns whether an engine is registered under a name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+
+    /// Returns the names of the registered engines in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constructors.keys().map(String::as_str)
+    }
+
+    /// Opens the engine registered under a name.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownEngine` if no engine is registered under the name.
+    ///
+    /// It propagates errors from the constructor of the engine.
+    pub fn open(&self, name: &str, config: &EngineConfig) -> Result<BoxedEngine> {
+        match self.constructors.get(name) {
+            Some(constructor) => constructor(config),
+            None => Err(KvsError::UnknownEngine(name.to_owned())),
+        }
+    }
+}
+
+impl Default for EngineRegistry {
+    fn default() -> Self {
+        let mut registry = EngineRegistry::new();
+        registry.register("kvs", |config| {
+            config.reject_memory_options()?;
+            let options = KvStoreOptions {
+                salvage: config.salvage,
+                cache_capacity: config.cache_capacity.unwrap_or(0),
+                ..KvStoreOptions::default()
+            };
+            let store =
+                KvStore::<RayonThreadPool>::open_with(&config.path, config.concurrency, options)?;
+            if let Some(report) = store.salvage_report() {
+                log_salvage_report(report);
+            }
+            Ok(store)
+        });
+        registry.register("sled", |config| {
+            config.reject_kvs_options()?;
+            config.reject_memory_options()?;
+            SledKvsEngine::<RayonThreadPool>::new(sled::open(&config.path)?, config.concurrency)
+        });
+        // With a snapshot, the data is saved by `flush`, which kvs-server calls on
+        // shutdown, and when the last handle is dropped, and loaded again at the next open.
+        registry.register("memory", |config| {
+            config.reject_kvs_options()?;
+            if config.memory_snapshot {
+                MemKvsEngine::open(config.path.join(MEMORY_SNAPSHOT))
+            } else {
+                Ok(MemKvsEngine::new())
+            }
+        });
+        registry.register("lsm", |config| {
+            config.reject_kvs_options()?;
+            config.reject_memory_options()?;
+            LsmStore::<RayonThreadPool>::open(&config.path, config.concurrency)
+        });
+        registry
+    }
+}
+
+impl fmt::Debug for EngineRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+fn log_salvage_report(report: &SalvageReport) {
+    if report.damaged_gens.is_empty() {
+        info!("Salvage mode: no corrupt records found");
+        return;
+    }
+    warn!(
+        "Salvage mode: lost {} records ({} bytes) in generations {:?}",
+        report.lost_records, report.lost_bytes, report.damaged_gens
+    );
+    warn!(
+        "Salvage mode: {} keys may have lost their latest value: {:?}",
+        report.lost_keys.len(),
+        report.lost_keys
+    );
+}
@@ -3,7 +3,7 @@ use sled::Db;
 
 use smol::channel::bounded;
 
-use super::KvsEngine;
+use super::{EngineStats, KvsEngine};
 use crate::{KvsError, Result, ThreadPool};
 
 /// Wrapper of `sled::Db`
@@ -93,4 +93,125 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
 
         rx.recv().await?
     }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let db = self.db.clone();
+        let (tx, rx) = bounded(1);
+        self.pool.spawn(move || {
+            let res = (move || {
+                // `update_and_fetch` cannot propagate errors from the merge function,
+                // so the error of the last attempt is kept aside.
+                let mut err = None;
+                let new_value = db.update_and_fetch(key, |old| {
+                    err = None;
+                    let value = match old.map(parse_integer).transpose() {
+                        Ok(value) => value.unwrap_or(0),
+                        Err(e) => {
+                            err = Some(e);
+                            return old.map(<[u8]>::to_vec);
+                        }
+                    };
+                    match value.checked_add(delta) {
+                        Some(value) => Some(value.to_string().into_bytes()),
+                        None => {
+                            err = Some(KvsError::IntegerOverflow);
+                            old.map(<[u8]>::to_vec)
+                        }
+                    }
+                })?;
+                if let Some(e) = err {
+                    return Err(e);
+                }
+                db.flush()?;
+                parse_integer(&new_value.expect("value is set by the merge function"))
+            })();
+
+            smol::block_on(async {
+                if tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        let db = self.db.clone();
+        let (tx, rx) = bounded(1);
+        self.pool.spawn(move || {
+            let res = (move || {
+                let new_value = db.update_and_fetch(key, |old| {
+                    let mut new_value = old.map(<[u8]>::to_vec).unwrap_or_default();
+                    new_value.extend_from_slice(value.as_bytes());
+                    Some(new_value)
+                })?;
+                db.flush()?;
+                Ok(String::from_utf8(
+                    new_value
+                        .expect("value is set by the merge function")
+                        .to_vec(),
+                )?) as Result<String>
+            })();
+
+            smol::block_on(async {
+                if tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let db = self.db.clone();
+        let (tx, rx) = bounded(1);
+        self.pool.spawn(move || {
+            let res = db.flush().map(|_| ()).map_err(KvsError::from);
+
+            smol::block_on(async {
+                if tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        let db = self.db.clone();
+        let (tx, rx) = bounded(1);
+        self.pool.spawn(move || {
+            let res = (move || {
+                let mut live_bytes = 0;
+                for entry in db.iter() {
+                    let (key, value) = entry?;
+                    live_bytes += (key.len() + value.len()) as u64;
+                }
+                Ok(EngineStats {
+                    keys: db.len() as u64,
+                    live_bytes,
+                    disk_size: db.size_on_disk()?,
+                    ..EngineStats::default()
+                }) as Result<EngineStats>
+            })();
+
+            smol::block_on(async {
+                if tx.send(res).await.is_err() {
+                    error!("Receiving end is dropped");
+                }
+            })
+        });
+
+        rx.recv().await?
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(KvsError::NotAnInteger)
 }
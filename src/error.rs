@@ -20,6 +20,30 @@ pub enum KvsError {
     #[error("Key not found")]
     KeyNotFound,
 
+    /// The existing value is not an integer.
+    #[error("Value is not an integer")]
+    NotAnInteger,
+
+    /// Integer overflow during an arithmetic operation.
+    #[error("Integer overflow")]
+    IntegerOverflow,
+
+    /// The store rejects writes after a write failure until it is recovered.
+    #[error("Store is read-only after a write failure: {}", .0)]
+    ReadOnly(String),
+
+    /// The operation is not supported by the engine.
+    #[error("{} is not supported by the engine", .0)]
+    Unsupported(&'static str),
+
+    /// A key or value is larger than the limit of a `Limits` engine.
+    #[error("{} of {} bytes is larger than the limit of {} bytes", .0, .1, .2)]
+    TooLarge(&'static str, usize, usize),
+
+    /// No engine is registered under the name.
+    #[error("Unknown engine: {}", .0)]
+    UnknownEngine(String),
+
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[error("Unexpected command type")]
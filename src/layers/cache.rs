@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use super::Layer;
+use crate::{EngineStats, KvsEngine, Result, UserMeta, ValueMeta, ValueStream};
+
+/// A layer caching the values read from an engine in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheLayer {
+    capacity: u64,
+}
+
+impl CacheLayer {
+    /// Creates a `CacheLayer` holding up to `capacity` bytes of keys and values.
+    pub fn new(capacity: u64) -> Self {
+        CacheLayer { capacity }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for CacheLayer {
+    type Engine = ReadCache<E>;
+
+    fn layer(&self, inner: E) -> ReadCache<E> {
+        ReadCache {
+            inner,
+            cache: Arc::new(Mutex::new(Lru::new(self.capacity))),
+        }
+    }
+}
+
+/// An engine answering `get` and `get_many` from a cache of the values of the inner
+/// engine, evicting the least recently used values beyond its capacity.
+///
+/// Every write through the engine invalidates the cached value of its key, so the
+/// cache is only consistent if the inner engine is not written to otherwise. Clones
+/// share the cache.
+#[derive(Clone)]
+pub struct ReadCache<E> {
+    inner: E,
+    cache: Arc<Mutex<Lru>>,
+}
+
+struct Lru {
+    capacity: u64,
+    // the total size of the cached keys and values
+    size: u64,
+    tick: u64,
+    // bumped by every write, so a value read before a write is not cached after it
+    epoch: u64,
+    // key -> (value, tick of the last use)
+    entries: HashMap<String, (String, u64)>,
+    // tick of the last use -> key
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn new(capacity: u64) -> Self {
+        Lru {
+            capacity,
+            size: 0,
+            tick: 0,
+            epoch: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.tick += 1;
+        let (value, tick) = self.entries.get_mut(key)?;
+        let key = self.order.remove(tick).expect("cached keys are ordered");
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    /// Caches a value read at `epoch` unless a write happened since.
+    fn insert(&mut self, epoch: u64, key: String, value: String) {
+        let size = (key.len() + value.len()) as u64;
+        if epoch != self.epoch || size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + size > self.capacity {
+            let (_, lru_key) = self.order.pop_first().expect("a full cache is not empty");
+            self.remove_entry(&lru_key);
+        }
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Invalidates the value of a key being written.
+    fn invalidate(&mut self, key: &str) {
+        self.epoch += 1;
+        self.remove(key);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, tick)) = self.entries.get(key) {
+            self.order.remove(tick);
+            self.remove_entry(key);
+        }
+    }
+
+    fn remove_entry(&mut self, key: &str) {
+        if let Some((value, _)) = self.entries.remove(key) {
+            self.size -= (key.len() + value.len()) as u64;
+        }
+    }
+}
+
+impl<E: KvsEngine> ReadCache<E> {
+    fn invalidate<T>(&self, key: &str, res: Result<T>) -> Result<T> {
+        self.cache.lock().unwrap().invalidate(key);
+        res
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for ReadCache<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        let res = self.inner.set(key.clone(), value).await;
+        self.invalidate(&key, res)
+    }
+
+    /// Gets the value from the cache, or from the inner engine and caches it.
+    ///
+    /// Keys which do not exist are not cached.
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        let epoch = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(&key) {
+                return Ok(Some(value));
+            }
+            cache.epoch
+        };
+        let value = self.inner.get(key.clone()).await?;
+        if let Some(value) = &value {
+            self.cache.lock().unwrap().insert(epoch, key, value.clone());
+        }
+        Ok(value)
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let res = self.inner.set_with_meta(key.clone(), value, meta).await;
+        self.invalidate(&key, res)
+    }
+
+    /// The metadata is not cached, so this always reads from the inner engine.
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        self.inner.get_with_meta(key).await
+    }
+
+    /// Gets the cached values from the cache and the others from the inner engine in
+    /// a single request.
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let (mut values, epoch) = {
+            let mut cache = self.cache.lock().unwrap();
+            let values: Vec<_> = keys.iter().map(|key| cache.get(key)).collect();
+            (values, cache.epoch)
+        };
+        let missing: Vec<_> = keys
+            .iter()
+            .zip(&values)
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+
+        let mut read = self.inner.get_many(missing).await?.into_iter();
+        let mut cache = self.cache.lock().unwrap();
+        for (key, value) in keys.into_iter().zip(&mut values) {
+            if value.is_some() {
+                continue;
+            }
+            *value = read.next().expect("a value is read for every missing key");
+            if let Some(value) = value {
+                cache.insert(epoch, key, value.clone());
+            }
+        }
+        Ok(values)
+    }
+
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        let res = self.inner.set_stream(key.clone(), chunks).await;
+        self.invalidate(&key, res)
+    }
+
+    /// Streamed values are not cached, so this always reads from the inner engine.
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        self.inner.get_stream(key).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        let res = self.inner.remove(key.clone()).await;
+        self.invalidate(&key, res)
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let res = self.inner.incr(key.clone(), delta).await;
+        self.invalidate(&key, res)
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        let res = self.inner.decr(key.clone(), delta).await;
+        self.invalidate(&key, res)
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        let res = self.inner.append(key.clone(), value).await;
+        self.invalidate(&key, res)
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        self.inner.stats().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        self.inner.set_compaction_rate_limit(bytes_per_sec).await
+    }
+}
@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use smol::channel;
+
+use super::Layer;
+use crate::{EngineStats, KvsEngine, KvsError, Result, UserMeta, ValueMeta, ValueStream};
+
+/// A layer rejecting writes of keys or values larger than the limits.
+///
+/// A limit of `None` leaves that size unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct LimitsLayer {
+    /// The maximum size of a key in bytes.
+    pub max_key_size: Option<usize>,
+    /// The maximum size of a value in bytes.
+    pub max_value_size: Option<usize>,
+}
+
+impl<E: KvsEngine> Layer<E> for LimitsLayer {
+    type Engine = Limits<E>;
+
+    fn layer(&self, inner: E) -> Limits<E> {
+        Limits {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+/// An engine rejecting writes to the inner engine which exceed the size limits.
+///
+/// Reads are passed through, since a key larger than the limit cannot exist.
+#[derive(Clone)]
+pub struct Limits<E> {
+    inner: E,
+    limits: LimitsLayer,
+}
+
+impl<E> Limits<E> {
+    fn check_key(&self, key: &str) -> Result<()> {
+        check("Key", key.len(), self.limits.max_key_size)
+    }
+
+    fn check_value(&self, value: &str) -> Result<()> {
+        check("Value", value.len(), self.limits.max_value_size)
+    }
+}
+
+fn check(subject: &'static str, size: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(KvsError::TooLarge(subject, size, limit)),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for Limits<E> {
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key or the value exceeds its limit.
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_key(&key)?;
+        self.check_value(&value)?;
+        self.inner.set(key, value).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key).await
+    }
+
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key or the value exceeds its limit.
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        self.check_key(&key)?;
+        self.check_value(&value)?;
+        self.inner.set_with_meta(key, value, meta).await
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        self.inner.get_with_meta(key).await
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.inner.get_many(keys).await
+    }
+
+    /// The chunks are forwarded to the inner engine until the value exceeds the limit,
+    /// when an error is sent instead, so the value is never set.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key or the value exceeds its limit.
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        self.check_key(&key)?;
+        let limit = match self.limits.max_value_size {
+            Some(limit) => limit,
+            None => return self.inner.set_stream(key, chunks).await,
+        };
+
+        let (tx, rx) = channel::bounded(1);
+        smol::spawn(async move {
+            let mut size = 0;
+            while let Ok(chunk) = chunks.recv().await {
+                let chunk = chunk.and_then(|chunk| {
+                    size += chunk.len();
+                    check("Value", size, Some(limit))?;
+                    Ok(chunk)
+                });
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        })
+        .detach();
+        self.inner.set_stream(key, rx).await
+    }
+
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        self.inner.get_stream(key).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key).await
+    }
+
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key exceeds its limit.
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.check_key(&key)?;
+        self.inner.incr(key, delta).await
+    }
+
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key exceeds its limit.
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.check_key(&key)?;
+        self.inner.decr(key, delta).await
+    }
+
+    /// The value after the append is checked against the value limit, using the current
+    /// value read before the append. A concurrent write to the same key may still grow
+    /// the value past the limit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TooLarge` if the key or the value after the append exceeds
+    /// its limit.
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        self.check_key(&key)?;
+        self.check_value(&value)?;
+        if self.limits.max_value_size.is_some() {
+            let current = self.inner.get(key.clone()).await?;
+            let size = current.map_or(0, |current| current.len()) + value.len();
+            check("Value", size, self.limits.max_value_size)?;
+        }
+        self.inner.append(key, value).await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        self.inner.stats().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        self.inner.set_compaction_rate_limit(bytes_per_sec).await
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use log::Level;
+
+use super::Layer;
+use crate::{EngineStats, KvsEngine, Result, UserMeta, ValueMeta, ValueStream};
+
+/// A layer logging every request to an engine with its key, duration and outcome.
+///
+/// Values are never logged.
+#[derive(Debug, Clone, Copy)]
+pub struct LoggingLayer {
+    level: Level,
+}
+
+impl LoggingLayer {
+    /// Creates a `LoggingLayer` logging at the given level.
+    pub fn new(level: Level) -> Self {
+        LoggingLayer { level }
+    }
+}
+
+impl Default for LoggingLayer {
+    /// Logs at the info level.
+    fn default() -> Self {
+        LoggingLayer::new(Level::Info)
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for LoggingLayer {
+    type Engine = Logging<E>;
+
+    fn layer(&self, inner: E) -> Logging<E> {
+        Logging {
+            inner,
+            level: self.level,
+        }
+    }
+}
+
+/// An engine logging the requests to the inner engine.
+#[derive(Clone)]
+pub struct Logging<E> {
+    inner: E,
+    level: Level,
+}
+
+impl<E: KvsEngine> Logging<E> {
+    async fn log<T, F>(&self, op: &str, subject: impl Display, request: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let start = Instant::now();
+        let res = request.await;
+        match &res {
+            Ok(_) => log!(self.level, "{} {} took {:?}", op, subject, start.elapsed()),
+            Err(e) => log!(
+                self.level,
+                "{} {} failed after {:?}: {}",
+                op,
+                subject,
+                start.elapsed(),
+                e
+            ),
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for Logging<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.log("set", format!("{:?}", key), self.inner.set(key, value))
+            .await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.log("get", format!("{:?}", key), self.inner.get(key))
+            .await
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        let subject = format!("{:?}", key);
+        self.log(
+            "set_with_meta",
+            subject,
+            self.inner.set_with_meta(key, value, meta),
+        )
+        .await
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        let subject = format!("{:?}", key);
+        self.log("get_with_meta", subject, self.inner.get_with_meta(key))
+            .await
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let subject = format!("of {} keys", keys.len());
+        self.log("get_many", subject, self.inner.get_many(keys))
+            .await
+    }
+
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        let subject = format!("{:?}", key);
+        self.log("set_stream", subject, self.inner.set_stream(key, chunks))
+            .await
+    }
+
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        let subject = format!("{:?}", key);
+        self.log("get_stream", subject, self.inner.get_stream(key))
+            .await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.log("remove", format!("{:?}", key), self.inner.remove(key))
+            .await
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let subject = format!("{:?} by {}", key, delta);
+        self.log("incr", subject, self.inner.incr(key, delta)).await
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        let subject = format!("{:?} by {}", key, delta);
+        self.log("decr", subject, self.inner.decr(key, delta)).await
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        self.log(
+            "append",
+            format!("{:?}", key),
+            self.inner.append(key, value),
+        )
+        .await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        self.log("stats", "", self.inner.stats()).await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.log("compact", "", self.inner.compact()).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.log("flush", "", self.inner.flush()).await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        let subject = format!("to {} bytes/s", bytes_per_sec);
+        let request = self.inner.set_compaction_rate_limit(bytes_per_sec);
+        self.log("set_compaction_rate_limit", subject, request)
+            .await
+    }
+}
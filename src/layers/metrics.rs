@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use super::Layer;
+use crate::{EngineStats, KvsEngine, OpStats, Result, UserMeta, ValueMeta, ValueStream};
+
+/// A layer recording the requests, failures and time of each operation of an engine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsLayer;
+
+impl<E: KvsEngine> Layer<E> for MetricsLayer {
+    type Engine = Metrics<E>;
+
+    fn layer(&self, inner: E) -> Metrics<E> {
+        Metrics::new(inner)
+    }
+}
+
+/// An engine recording metrics of the operations of the inner engine.
+///
+/// The metrics are reported by `stats` in `EngineStats::ops`. Clones share the metrics.
+#[derive(Clone)]
+pub struct Metrics<E> {
+    inner: E,
+    ops: Arc<Mutex<BTreeMap<&'static str, OpStats>>>,
+}
+
+impl<E: KvsEngine> Metrics<E> {
+    /// Wraps an engine with no metrics recorded yet.
+    pub fn new(inner: E) -> Self {
+        Metrics {
+            inner,
+            ops: Arc::default(),
+        }
+    }
+
+    /// Returns the metrics of the operations requested so far in the order of their
+    /// names.
+    pub fn snapshot(&self) -> Vec<OpStats> {
+        self.ops.lock().unwrap().values().cloned().collect()
+    }
+
+    async fn record<T, F>(&self, op: &'static str, request: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let start = Instant::now();
+        let res = request.await;
+        let mut ops = self.ops.lock().unwrap();
+        let stats = ops.entry(op).or_insert_with(|| OpStats {
+            op: op.to_owned(),
+            ..OpStats::default()
+        });
+        stats.requests += 1;
+        if res.is_err() {
+            stats.errors += 1;
+        }
+        stats.total_time += start.elapsed();
+        res
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for Metrics<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.record("set", self.inner.set(key, value)).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.record("get", self.inner.get(key)).await
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        self.record("set_with_meta", self.inner.set_with_meta(key, value, meta))
+            .await
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        self.record("get_with_meta", self.inner.get_with_meta(key))
+            .await
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.record("get_many", self.inner.get_many(keys)).await
+    }
+
+    /// The time of a request includes receiving the whole value.
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        self.record("set_stream", self.inner.set_stream(key, chunks))
+            .await
+    }
+
+    /// The time of a request does not include sending the value.
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        self.record("get_stream", self.inner.get_stream(key)).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.record("remove", self.inner.remove(key)).await
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.record("incr", self.inner.incr(key, delta)).await
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.record("decr", self.inner.decr(key, delta)).await
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        self.record("append", self.inner.append(key, value)).await
+    }
+
+    /// Reports the metrics along with the statistics of the inner engine.
+    async fn stats(&self) -> Result<EngineStats> {
+        let mut stats = self.record("stats", self.inner.stats()).await?;
+        stats.ops.extend(self.snapshot());
+        Ok(stats)
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.record("compact", self.inner.compact()).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.record("flush", self.inner.flush()).await
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        let request = self.inner.set_compaction_rate_limit(bytes_per_sec);
+        self.record("set_compaction_rate_limit", request).await
+    }
+}
@@ -0,0 +1,196 @@
+//! This module provides layers which wrap a `KvsEngine` into another `KvsEngine`,
+//! adding behavior around the operations of any engine without modifying it. All
+//! layers should implement the `Layer` trait.
+
+mod cache;
+mod limits;
+mod logging;
+mod metrics;
+
+pub use self::cache::{CacheLayer, ReadCache};
+pub use self::limits::{Limits, LimitsLayer};
+pub use self::logging::{Logging, LoggingLayer};
+pub use self::metrics::{Metrics, MetricsLayer};
+
+use async_trait::async_trait;
+
+use crate::{EngineStats, KvsEngine, Result, UserMeta, ValueMeta, ValueStream};
+
+/// The trait that all layers should implement.
+///
+/// A layer is applied by `KvsEngine::with_layer`. Layers are composed with `Stack`, and
+/// an `Option` of a layer applies it only if it is `Some`.
+pub trait Layer<E: KvsEngine> {
+    /// The engine wrapping the inner one.
+    type Engine: KvsEngine;
+
+    /// Wraps an engine.
+    fn layer(&self, inner: E) -> Self::Engine;
+}
+
+/// A layer which applies the inner layer and then the outer one.
+#[derive(Debug, Clone, Default)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    /// Creates a `Stack` of two layers.
+    pub fn new(inner: Inner, outer: Outer) -> Self {
+        Stack { inner, outer }
+    }
+}
+
+impl<E, Inner, Outer> Layer<E> for Stack<Inner, Outer>
+where
+    E: KvsEngine,
+    Inner: Layer<E>,
+    Outer: Layer<Inner::Engine>,
+{
+    type Engine = Outer::Engine;
+
+    fn layer(&self, inner: E) -> Self::Engine {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// A layer which leaves an engine as it is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<E: KvsEngine> Layer<E> for Identity {
+    type Engine = E;
+
+    fn layer(&self, inner: E) -> E {
+        inner
+    }
+}
+
+impl<E: KvsEngine, L: Layer<E>> Layer<E> for Option<L> {
+    type Engine = Either<L::Engine, E>;
+
+    fn layer(&self, inner: E) -> Self::Engine {
+        match self {
+            Some(layer) => Either::Left(layer.layer(inner)),
+            None => Either::Right(inner),
+        }
+    }
+}
+
+/// One of two engines, which is what an `Option` of a layer wraps an engine into.
+#[derive(Debug, Clone)]
+pub enum Either<A, B> {
+    /// The engine wrapped by the layer.
+    Left(A),
+    /// The engine left as it is.
+    Right(B),
+}
+
+#[async_trait]
+impl<A: KvsEngine, B: KvsEngine> KvsEngine for Either<A, B> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.set(key, value).await,
+            Either::Right(engine) => engine.set(key, value).await,
+        }
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            Either::Left(engine) => engine.get(key).await,
+            Either::Right(engine) => engine.get(key).await,
+        }
+    }
+
+    async fn set_with_meta(&self, key: String, value: String, meta: UserMeta) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.set_with_meta(key, value, meta).await,
+            Either::Right(engine) => engine.set_with_meta(key, value, meta).await,
+        }
+    }
+
+    async fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        match self {
+            Either::Left(engine) => engine.get_with_meta(key).await,
+            Either::Right(engine) => engine.get_with_meta(key).await,
+        }
+    }
+
+    async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self {
+            Either::Left(engine) => engine.get_many(keys).await,
+            Either::Right(engine) => engine.get_many(keys).await,
+        }
+    }
+
+    async fn set_stream(&self, key: String, chunks: ValueStream) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.set_stream(key, chunks).await,
+            Either::Right(engine) => engine.set_stream(key, chunks).await,
+        }
+    }
+
+    async fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        match self {
+            Either::Left(engine) => engine.get_stream(key).await,
+            Either::Right(engine) => engine.get_stream(key).await,
+        }
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.remove(key).await,
+            Either::Right(engine) => engine.remove(key).await,
+        }
+    }
+
+    async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        match self {
+            Either::Left(engine) => engine.incr(key, delta).await,
+            Either::Right(engine) => engine.incr(key, delta).await,
+        }
+    }
+
+    async fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        match self {
+            Either::Left(engine) => engine.decr(key, delta).await,
+            Either::Right(engine) => engine.decr(key, delta).await,
+        }
+    }
+
+    async fn append(&self, key: String, value: String) -> Result<String> {
+        match self {
+            Either::Left(engine) => engine.append(key, value).await,
+            Either::Right(engine) => engine.append(key, value).await,
+        }
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        match self {
+            Either::Left(engine) => engine.stats().await,
+            Either::Right(engine) => engine.stats().await,
+        }
+    }
+
+    async fn compact(&self) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.compact().await,
+            Either::Right(engine) => engine.compact().await,
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.flush().await,
+            Either::Right(engine) => engine.flush().await,
+        }
+    }
+
+    async fn set_compaction_rate_limit(&self, bytes_per_sec: u64) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.set_compaction_rate_limit(bytes_per_sec).await,
+            Either::Right(engine) => engine.set_compaction_rate_limit(bytes_per_sec).await,
+        }
+    }
+}
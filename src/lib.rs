@@ -5,14 +5,25 @@
 extern crate log;
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    BoxedEngine, DynEngine, EngineConfig, EngineRegistry, EngineStats, HistoryRetention,
+    KeyVersion, KvStore, KvStoreOptions, KvsEngine, LsmOptions, LsmStore, MemKvsEngine, OpStats,
+    SalvageReport, SledKvsEngine, UserMeta, ValueMeta, ValueStream,
+};
 pub use error::{KvsError, Result};
+pub use layers::{
+    CacheLayer, Either, Identity, Layer, Limits, LimitsLayer, Logging, LoggingLayer, Metrics,
+    MetricsLayer, ReadCache, Stack,
+};
 pub use server::{run_with, KvsServer, ADDRESS_FORMAT, DEFAULT_LISTENING_ADDRESS};
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use vfs::{MappedFile, MemVfs, OsVfs, Vfs, VfsFile};
 
 mod client;
 mod common;
 mod engines;
 mod error;
+mod layers;
 mod server;
 mod thread_pool;
+mod vfs;
@@ -1,13 +1,17 @@
 use std::convert::TryInto;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 
-use smol::io::{BufReader, BufWriter};
+use smol::channel::{bounded, Sender};
+use smol::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
 use smol::prelude::{AsyncReadExt, AsyncWriteExt};
 use smol::Async;
 
-use crate::common::{PacketSize, Request, Response};
+use crate::common::{read_chunk, write_chunk, PacketSize, Request, Response, CHUNK_SIZE};
 use crate::{KvsEngine, Result};
 
+// the number of received chunks of a streamed value buffered for the engine
+const STREAM_BUFFER: usize = 4;
+
 /// The default listening ADDRESS of KvsServer
 pub const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 
@@ -65,14 +69,74 @@ async fn serve<E: KvsEngine>(engine: E, stream: Async<TcpStream>) -> Result<()>
             Ok(value) => Response::Get(value),
             Err(e) => Response::Err(format!("{}", e)),
         },
+        Request::GetWithMeta { key } => match engine.get_with_meta(key).await {
+            Ok(value) => Response::GetWithMeta(value),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::GetMany { keys } => match engine.get_many(keys).await {
+            Ok(values) => Response::GetMany(values),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
         Request::Set { key, value } => match engine.set(key, value).await {
             Ok(_) => Response::Set,
             Err(e) => Response::Err(format!("{}", e)),
         },
+        Request::SetWithMeta { key, value, meta } => {
+            match engine.set_with_meta(key, value, meta).await {
+                Ok(_) => Response::SetWithMeta,
+                Err(e) => Response::Err(format!("{}", e)),
+            }
+        }
+        Request::SetStream { key } => {
+            let (tx, rx) = bounded(STREAM_BUFFER);
+            let set = smol::spawn({
+                let engine = engine.clone();
+                async move { engine.set_stream(key, rx).await }
+            });
+            if let Err(e) = receive_value(&mut reader, &tx).await {
+                let _ = tx.send(Err(e)).await;
+            }
+            drop(tx);
+            match set.await {
+                Ok(_) => Response::SetStream,
+                Err(e) => Response::Err(format!("{}", e)),
+            }
+        }
+        Request::GetStream { key } => return send_value(&engine, key, &mut writer).await,
         Request::Remove { key } => match engine.remove(key).await {
             Ok(_) => Response::Remove,
             Err(e) => Response::Err(format!("{}", e)),
         },
+        Request::Incr { key, delta } => match engine.incr(key, delta).await {
+            Ok(value) => Response::Incr(value),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::Decr { key, delta } => match engine.decr(key, delta).await {
+            Ok(value) => Response::Decr(value),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::Append { key, value } => match engine.append(key, value).await {
+            Ok(value) => Response::Append(value),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::Stats => match engine.stats().await {
+            Ok(stats) => Response::Stats(stats),
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::Compact => match engine.compact().await {
+            Ok(_) => Response::Compact,
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::Flush => match engine.flush().await {
+            Ok(_) => Response::Flush,
+            Err(e) => Response::Err(format!("{}", e)),
+        },
+        Request::SetCompactionRateLimit { bytes_per_sec } => {
+            match engine.set_compaction_rate_limit(bytes_per_sec).await {
+                Ok(_) => Response::SetCompactionRateLimit,
+                Err(e) => Response::Err(format!("{}", e)),
+            }
+        }
     };
     let j = serde_json::to_vec(&res)?;
     writer.write(&j).await?;
@@ -80,3 +144,49 @@ async fn serve<E: KvsEngine>(engine: E, stream: Async<TcpStream>) -> Result<()>
 
     Ok(())
 }
+
+/// Forwards the chunks of a streamed value to the engine until the empty chunk which
+/// ends the value.
+async fn receive_value<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    tx: &Sender<Result<Vec<u8>>>,
+) -> Result<()> {
+    let mut forwarding = true;
+    loop {
+        let chunk = read_chunk(reader).await?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        // If the engine stops early, e.g. after an error, the rest of the value is still
+        // read so that the client gets the response.
+        if forwarding && tx.send(Ok(chunk)).await.is_err() {
+            forwarding = false;
+        }
+    }
+}
+
+/// Sends the response to a `GetStream` request followed by the chunks of the value.
+///
+/// A failure while reading the value closes the connection without the empty chunk
+/// which ends the value.
+async fn send_value<E: KvsEngine, W: AsyncWrite + Unpin>(
+    engine: &E,
+    key: String,
+    writer: &mut W,
+) -> Result<()> {
+    let (res, chunks) = match engine.get_stream(key).await {
+        Ok(chunks) => (Response::GetStream(chunks.is_some()), chunks),
+        Err(e) => (Response::Err(format!("{}", e)), None),
+    };
+    write_chunk(writer, &serde_json::to_vec(&res)?).await?;
+    if let Some(chunks) = chunks {
+        while let Ok(chunk) = chunks.recv().await {
+            for part in chunk?.chunks(CHUNK_SIZE) {
+                write_chunk(writer, part).await?;
+            }
+        }
+        write_chunk(writer, &[]).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::{MappedFile, Vfs, VfsFile};
+
+// the error number of "No space left on device" on Linux and macOS
+const ENOSPC: i32 = 28;
+
+/// An in-memory filesystem for deterministic tests, which can inject faults.
+///
+/// Like a real disk, written data survives a simulated `crash` only after the file is
+/// synced, and created, renamed or removed files only after their directory is synced.
+/// A crash may keep a part of the data written after the last sync, which leaves a torn
+/// write at the end of the file.
+///
+/// Clones share the same filesystem.
+#[derive(Debug, Clone, Default)]
+pub struct MemVfs {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    dirs: BTreeSet<PathBuf>,
+    // the namespace seen by the operations
+    files: BTreeMap<PathBuf, Arc<Mutex<Node>>>,
+    // the namespace as of the last directory syncs, which survives a crash
+    durable_files: BTreeMap<PathBuf, Arc<Mutex<Node>>>,
+    // the total size of the files beyond which writes fail, if any
+    capacity: Option<u64>,
+    short_writes: bool,
+    fail_reads: bool,
+    fail_writes: bool,
+    fail_syncs: bool,
+    // the number of modifying operations before a simulated crash, if any
+    crash_countdown: Option<u64>,
+    // the file whose next sync is replaced by a simulated crash, if any
+    crash_on_sync: Option<PathBuf>,
+    // whether a simulated crash happened and the operations fail until `recover`
+    halted: bool,
+    // the state of the generator choosing how much unsynced data a crash keeps
+    rng: u64,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    data: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+impl MemVfs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        MemVfs::default()
+    }
+
+    /// Creates an empty filesystem whose crashes keep the parts of the unsynced data
+    /// chosen by the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        let vfs = MemVfs::default();
+        vfs.state.lock().unwrap().rng = seed;
+        vfs
+    }
+
+    /// Limits the total size of the files. A write beyond it is applied partially
+    /// and fails like on a full disk. `None` means unlimited.
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        self.state.lock().unwrap().capacity = capacity;
+    }
+
+    /// Makes every write write only a part of the given buffer.
+    pub fn set_short_writes(&self, short_writes: bool) {
+        self.state.lock().unwrap().short_writes = short_writes;
+    }
+
+    /// Makes every read of a file fail.
+    pub fn set_fail_reads(&self, fail_reads: bool) {
+        self.state.lock().unwrap().fail_reads = fail_reads;
+    }
+
+    /// Makes every write fail.
+    pub fn set_fail_writes(&self, fail_writes: bool) {
+        self.state.lock().unwrap().fail_writes = fail_writes;
+    }
+
+    /// Makes every file or directory sync fail.
+    pub fn set_fail_syncs(&self, fail_syncs: bool) {
+        self.state.lock().unwrap().fail_syncs = fail_syncs;
+    }
+
+    /// Simulates a crash: drops the directory entries which are not synced, and all but
+    /// an arbitrary prefix of the data written to each file after it was last synced.
+    ///
+    /// Files opened before the crash are detached from the filesystem.
+    pub fn crash(&self) {
+        self.state.lock().unwrap().crash();
+    }
+
+    /// Simulates a crash in place of the given number of following write, sync,
+    /// create, rename or remove operations. `None` cancels a pending crash.
+    ///
+    /// Once crashed, every operation fails like in a dead process until `recover`.
+    pub fn crash_after(&self, ops: Option<u64>) {
+        self.state.lock().unwrap().crash_countdown = ops;
+    }
+
+    /// Simulates a crash in place of the next sync of the file at the given path, after
+    /// the data written to it. `None` cancels a pending crash.
+    ///
+    /// Once crashed, every operation fails like in a dead process until `recover`.
+    pub fn crash_on_sync(&self, path: Option<PathBuf>) {
+        self.state.lock().unwrap().crash_on_sync = path;
+    }
+
+    /// Returns whether a crash set by `crash_after` or `crash_on_sync` has happened.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().halted
+    }
+
+    /// Makes the filesystem usable again after a crash set by `crash_after` or
+    /// `crash_on_sync`.
+    pub fn recover(&self) {
+        self.state.lock().unwrap().halted = false;
+    }
+
+    fn node(&self, path: &Path) -> io::Result<Arc<Mutex<Node>>> {
+        let state = self.state.lock().unwrap();
+        state.check_alive()?;
+        state.files.get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn file(&self, node: Arc<Mutex<Node>>, append: bool) -> Box<dyn VfsFile> {
+        Box::new(MemFile {
+            vfs: self.clone(),
+            node,
+            pos: 0,
+            append,
+        })
+    }
+}
+
+impl State {
+    fn crash(&mut self) {
+        let mut rng = self.rng;
+        let files: BTreeMap<_, _> = self
+            .durable_files
+            .iter()
+            .map(|(path, node)| {
+                let node = node.lock().unwrap();
+                let mut data = node.synced.clone();
+                // Unsynced appends may have partially reached the disk.
+                if node.data.starts_with(&node.synced) {
+                    let unsynced = &node.data[node.synced.len()..];
+                    let kept = next_random(&mut rng) % (unsynced.len() as u64 + 1);
+                    data.extend_from_slice(&unsynced[..kept as usize]);
+                }
+                let node = Node {
+                    synced: data.clone(),
+                    data,
+                };
+                (path.clone(), Arc::new(Mutex::new(node)))
+            })
+            .collect();
+        self.rng = rng;
+        self.durable_files = files.clone();
+        self.files = files;
+    }
+
+    /// Simulates a crash after which every operation fails until `recover`.
+    fn halt(&mut self) -> io::Result<()> {
+        self.crash();
+        self.halted = true;
+        Err(injected("crash"))
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        if self.halted {
+            Err(injected("crash"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts a modifying operation, which crashes if the countdown runs out.
+    fn tick(&mut self) -> io::Result<()> {
+        self.check_alive()?;
+        match self.crash_countdown {
+            Some(0) => {
+                self.crash_countdown = None;
+                self.halt()
+            }
+            Some(ops) => {
+                self.crash_countdown = Some(ops - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if self.dirs.contains(dir) => Ok(()),
+            _ => Err(not_found()),
+        }
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.files
+            .values()
+            .map(|node| node.lock().unwrap().data.len() as u64)
+            .sum()
+    }
+}
+
+impl Vfs for MemVfs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_owned());
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(self.file(self.node(path)?, false))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick()?;
+        state.check_dir(path)?;
+        let node = state.files.entry(path.to_owned()).or_default().clone();
+        node.lock().unwrap().data.clear();
+        drop(state);
+        Ok(self.file(node, false))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick()?;
+        state.check_dir(path)?;
+        let node = state.files.entry(path.to_owned()).or_default().clone();
+        drop(state);
+        Ok(self.file(node, true))
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.node(path)?.lock().unwrap().data.len() as u64)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        state.check_alive()?;
+        if !state.dirs.contains(path) {
+            return Err(not_found());
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|file| file.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.tick()?;
+        state.check_dir(to)?;
+        let node = state.files.remove(from).ok_or_else(not_found)?;
+        state.files.insert(to.to_owned(), node);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.tick()?;
+        state.files.remove(path).map(|_| ()).ok_or_else(not_found)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.tick()?;
+        if state.fail_syncs {
+            return Err(injected("sync"));
+        }
+        if !state.dirs.contains(path) {
+            return Err(not_found());
+        }
+        let State {
+            files,
+            durable_files,
+            ..
+        } = &mut *state;
+        durable_files.retain(|file, _| file.parent() != Some(path));
+        for (file, node) in files.iter() {
+            if file.parent() == Some(path) {
+                durable_files.insert(file.clone(), Arc::clone(node));
+            }
+        }
+        Ok(())
+    }
+
+    fn map(&self, path: &Path) -> io::Result<MappedFile> {
+        let data = self.node(path)?.lock().unwrap().data.clone();
+        Ok(Arc::new(data))
+    }
+}
+
+/// A file handle of a `MemVfs`.
+struct MemFile {
+    vfs: MemVfs,
+    node: Arc<Mutex<Node>>,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.vfs.state.lock().unwrap();
+        state.check_alive()?;
+        if state.fail_reads {
+            return Err(injected("read"));
+        }
+        drop(state);
+        let node = self.node.lock().unwrap();
+        let start = cmp::min(self.pos, node.data.len() as u64) as usize;
+        let len = cmp::min(buf.len(), node.data.len() - start);
+        buf[..len].copy_from_slice(&node.data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.vfs.state.lock().unwrap();
+        state.tick()?;
+        if state.fail_writes {
+            return Err(injected("write"));
+        }
+        let mut len = buf.len();
+        if state.short_writes && len > 1 {
+            len /= 2;
+        }
+        let used_bytes = state.used_bytes();
+        let mut node = self.node.lock().unwrap();
+        if self.append {
+            self.pos = node.data.len() as u64;
+        }
+        let end = self.pos + len as u64;
+        if let Some(capacity) = state.capacity {
+            let grown = end.saturating_sub(node.data.len() as u64);
+            if used_bytes + grown > capacity {
+                let available = capacity.saturating_sub(used_bytes);
+                let writable = (node.data.len() as u64 + available).saturating_sub(self.pos);
+                len = cmp::min(len as u64, writable) as usize;
+                if len == 0 {
+                    return Err(io::Error::from_raw_os_error(ENOSPC));
+                }
+            }
+        }
+
+        let start = self.pos as usize;
+        if node.data.len() < start + len {
+            node.data.resize(start + len, 0);
+        }
+        node.data[start..start + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.node.lock().unwrap().data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for MemFile {
+    fn sync_all(&self) -> io::Result<()> {
+        let mut state = self.vfs.state.lock().unwrap();
+        state.tick()?;
+        if state.fail_syncs {
+            return Err(injected("sync"));
+        }
+        let crashes = match &state.crash_on_sync {
+            Some(path) => {
+                matches!(state.files.get(path), Some(node) if Arc::ptr_eq(node, &self.node))
+            }
+            None => false,
+        };
+        if crashes {
+            state.crash_on_sync = None;
+            return state.halt();
+        }
+        drop(state);
+        let mut node = self.node.lock().unwrap();
+        node.synced = node.data.clone();
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.vfs.state.lock().unwrap().tick()?;
+        self.node.lock().unwrap().data.resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+/// Returns the next number of a SplitMix64 sequence.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn not_found() -> io::Error {
+    io::Error::from(io::ErrorKind::NotFound)
+}
+
+fn injected(op: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("injected {} failure", op))
+}
@@ -0,0 +1,62 @@
+//! This module provides the filesystem used by `KvStore`. All filesystems should
+//! implement the `Vfs` trait.
+
+mod mem;
+mod os;
+
+pub use self::mem::MemVfs;
+pub use self::os::OsVfs;
+
+use std::fmt::Debug;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A file opened through a `Vfs`.
+pub trait VfsFile: Read + Write + Seek + Send {
+    /// Flushes the written data of the file to durable storage.
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Truncates or extends the file to the given size.
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+/// The read-only contents of a file mapped into memory.
+pub type MappedFile = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// The trait that all filesystems used by `KvStore` should implement.
+///
+/// A path names a file directly under a directory; the store does not create nested
+/// directories except for its own.
+pub trait Vfs: Debug + Send + Sync + 'static {
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Creates a file for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Returns the size of a file in bytes.
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Returns the paths of the files in a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Renames a file, replacing the destination if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Flushes the entries of a directory, e.g. created or renamed files, to durable
+    /// storage.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Maps a file which is not modified anymore into memory.
+    fn map(&self, path: &Path) -> io::Result<MappedFile>;
+}
@@ -171,6 +171,18 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_unknown_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "rocks", "--addr", "127.0.0.1:4001"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unknown engine: rocks"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -213,11 +225,18 @@ fn cli_wrong_engine() {
 }
 
 fn cli_access_server(engine: &str, addr: &str) {
+    // The memory engine keeps its data across restarts only with a snapshot.
+    let flags: &[&str] = if engine == "memory" {
+        &["--memory-snapshot"]
+    } else {
+        &[]
+    };
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(flags)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -267,6 +286,91 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(contains("Key not found"));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "key2", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\nvalue2\n");
+
+    let set_with_meta = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "typed",
+            "{}",
+            "--flags",
+            "7",
+            "--content-type",
+            "application/json",
+        ])
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert();
+    if engine != "sled" {
+        set_with_meta.success().stdout(is_empty());
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "typed", "--meta", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("{}\ncreated_ms: "))
+            .stdout(contains("\nflags: 7\ncontent_type: application/json\n"));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["rm", "typed", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    } else {
+        set_with_meta
+            .failure()
+            .stderr(contains("value metadata is not supported"));
+    }
+
+    // Streamed values are written to and read from files outside the server directory.
+    let files_dir = TempDir::new().unwrap();
+    let large_value = "large value 中😀\n".repeat(20000);
+    fs::write(files_dir.path().join("upload"), &large_value).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["upload", "large", "upload", "--addr", addr])
+        .current_dir(&files_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["download", "large", "download", "--addr", addr])
+        .current_dir(&files_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        fs::read_to_string(files_dir.path().join("download")).unwrap(),
+        large_value
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["download", "missing", "missing", "--addr", addr])
+        .current_dir(&files_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    assert!(!files_dir.path().join("missing").exists());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "large", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key2", "--addr", addr])
@@ -291,6 +395,76 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["decr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["append", "key2", "_suffix", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3_suffix\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "flush", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let assert = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert();
+    if engine != "sled" {
+        assert.success().stdout(is_empty());
+    } else {
+        assert.failure().stderr(contains("not supported"));
+    }
+
+    let assert = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "rate-limit", "1048576", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert();
+    if engine == "kvs" {
+        assert.success().stdout(is_empty());
+    } else {
+        assert.failure().stderr(contains("not supported"));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -299,6 +473,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(flags)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -314,7 +489,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(contains("value3"));
+        .stdout(contains("value3_suffix"));
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["get", "key1", "--addr", addr])
@@ -335,3 +510,141 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_memory_engine() {
+    cli_access_server("memory", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4008");
+}
+
+// The layers selected by the server flags should apply to the requests
+#[test]
+fn cli_server_layers() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .args(&["--metrics", "--log-requests", "--read-cache", "1048576"])
+        .args(&["--max-key-size", "8", "--max-value-size", "16"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "long_key_1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("larger than the limit"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "a_very_long_value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("larger than the limit"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n"))
+        .stdout(contains("get: requests 1, errors 0"))
+        .stdout(contains("set: requests 3, errors 2"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Stopping the server with a signal should save the data of the memory engine if its
+// snapshot is enabled
+#[test]
+fn cli_memory_engine_shutdown() {
+    let addr = "127.0.0.1:4010";
+    let modes: [(&[&str], bool); 2] = [(&[], false), (&["--memory-snapshot"], true)];
+    for &(flags, saved) in &modes {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "memory", "--addr", addr])
+            .args(flags)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::new("kill")
+            .args(&["-TERM", &child.id().to_string()])
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(temp_dir.path().join("memory.snapshot").exists(), saved);
+
+        // Restart and check whether the value was saved
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "memory", "--addr", addr])
+            .args(flags)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let get = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        if saved {
+            get.stdout("value1\n");
+        } else {
+            get.stdout(contains("Key not found"));
+        }
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
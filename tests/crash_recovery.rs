@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, LsmOptions, LsmStore, MemVfs, RayonThreadPool, Result,
+};
+
+const DIR: &str = "/db";
+const KEYS: u32 = 20;
+const ROUNDS: u32 = 4;
+
+type State = BTreeMap<String, String>;
+
+#[derive(Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Flush,
+    Compact,
+}
+
+fn random_op(rng: &mut StdRng, model: &State) -> Op {
+    let key = format!("key{}", rng.gen_range(0, KEYS));
+    match rng.gen_range(0, 100) {
+        0..=69 => {
+            let len = rng.gen_range(1, 200);
+            Op::Set(key, rng.sample_iter(&Alphanumeric).take(len).collect())
+        }
+        70..=89 if model.contains_key(&key) => Op::Remove(key),
+        70..=94 => Op::Flush,
+        _ => Op::Compact,
+    }
+}
+
+fn apply(state: &State, op: &Op) -> State {
+    let mut state = state.clone();
+    match op {
+        Op::Set(key, value) => {
+            state.insert(key.clone(), value.clone());
+        }
+        Op::Remove(key) => {
+            state.remove(key);
+        }
+        Op::Flush | Op::Compact => {}
+    }
+    state
+}
+
+async fn execute<E: KvsEngine>(store: &E, op: &Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => store.set(key.clone(), value.clone()).await,
+        Op::Remove(key) => store.remove(key.clone()).await,
+        Op::Flush => store.flush().await,
+        Op::Compact => store.compact().await,
+    }
+}
+
+async fn read_all<E: KvsEngine>(store: &E) -> Result<State> {
+    let mut state = State::new();
+    for key_id in 0..KEYS {
+        let key = format!("key{}", key_id);
+        if let Some(value) = store.get(key.clone()).await? {
+            state.insert(key, value);
+        }
+    }
+    Ok(state)
+}
+
+// Runs random operations, crashes at a random filesystem operation and checks that the
+// reopened store holds the state after some prefix of the operations which includes
+// every durable one.
+fn torture<E, F>(seed: u64, sync_writes: bool, open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&MemVfs) -> Result<E>,
+{
+    let mut rng = StdRng::seed_from_u64(seed);
+    let vfs = MemVfs::with_seed(seed);
+
+    let mut model = State::new();
+    for round in 0..ROUNDS {
+        let store = open(&vfs)?;
+        // the states the store may recover to, starting from the last durable one
+        let mut candidates = vec![model.clone()];
+        vfs.crash_after(Some(rng.gen_range(0, 500)));
+
+        smol::block_on(async {
+            loop {
+                let op = random_op(&mut rng, &model);
+                let next = apply(&model, &op);
+                if execute(&store, &op).await.is_err() {
+                    assert!(vfs.crashed(), "seed {}: {:?} failed", seed, op);
+                    // the interrupted operation may be durable or not
+                    candidates.push(next);
+                    break;
+                }
+                model = next;
+                match op {
+                    Op::Flush | Op::Compact => candidates = vec![model.clone()],
+                    _ if sync_writes => candidates = vec![model.clone()],
+                    _ => candidates.push(model.clone()),
+                }
+            }
+        });
+
+        drop(store);
+        vfs.recover();
+        let store = open(&vfs)?;
+        let recovered = smol::block_on(read_all(&store))?;
+        assert!(
+            candidates.contains(&recovered),
+            "seed {} round {}: recovered state is not a prefix of the acknowledged operations",
+            seed,
+            round
+        );
+        model = recovered;
+    }
+    Ok(())
+}
+
+fn open_kvs(vfs: &MemVfs, sync_writes: bool) -> Result<KvStore<RayonThreadPool>> {
+    let options = KvStoreOptions {
+        max_segment_size: 2048,
+        snapshot_interval: 4096,
+        sync_writes,
+        vfs: Arc::new(vfs.clone()),
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with(DIR, 1, options)
+}
+
+fn open_lsm(vfs: &MemVfs, sync_writes: bool) -> Result<LsmStore<RayonThreadPool>> {
+    let options = LsmOptions {
+        memtable_size: 512,
+        block_size: 128,
+        level0_table_limit: 2,
+        table_size: 1024,
+        level_size_base: 2048,
+        level_size_multiplier: 2,
+        sync_writes,
+        vfs: Arc::new(vfs.clone()),
+        ..LsmOptions::default()
+    };
+    LsmStore::open_with(DIR, 1, options)
+}
+
+// Every write acknowledged before a flush or a compaction should survive a crash
+#[test]
+fn crash_recovery() -> Result<()> {
+    for seed in 0..50 {
+        torture(seed, false, |vfs| open_kvs(vfs, false))?;
+    }
+    Ok(())
+}
+
+// Every acknowledged write should survive a crash if writes are synced
+#[test]
+fn crash_recovery_sync_writes() -> Result<()> {
+    for seed in 0..50 {
+        torture(seed, true, |vfs| open_kvs(vfs, true))?;
+    }
+    Ok(())
+}
+
+// The same should hold for the LSM engine, whose memtable flushes and compactions
+// replace its files
+#[test]
+fn lsm_crash_recovery() -> Result<()> {
+    for seed in 0..50 {
+        torture(seed, false, |vfs| open_lsm(vfs, false))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn lsm_crash_recovery_sync_writes() -> Result<()> {
+    for seed in 0..50 {
+        torture(seed, true, |vfs| open_lsm(vfs, true))?;
+    }
+    Ok(())
+}
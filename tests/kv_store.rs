@@ -1,9 +1,17 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
 use rayon::prelude::*;
 use smol::Executor;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvStore, KvsEngine, RayonThreadPool, Result};
+use kvs::{
+    HistoryRetention, KvStore, KvStoreOptions, KvsEngine, KvsError, NaiveThreadPool,
+    RayonThreadPool, Result, UserMeta, ValueStream,
+};
 
 // Should get previously stored value
 #[test]
@@ -75,6 +83,240 @@ fn overwrite_value() -> Result<()> {
     })
 }
 
+// Should get the values of many keys across generations in the order of the keys
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    smol::block_on(async {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .await?;
+        }
+        assert!(log_file_sizes(temp_dir.path()).len() > 1);
+
+        let keys = vec!["key99", "missing", "key0", "key50", "key0"];
+        let values = store
+            .get_many(keys.into_iter().map(str::to_owned).collect())
+            .await?;
+        assert_eq!(
+            values,
+            vec![
+                Some("value99".to_owned()),
+                None,
+                Some("value0".to_owned()),
+                Some("value50".to_owned()),
+                Some("value0".to_owned()),
+            ]
+        );
+        assert!(store.get_many(Vec::new()).await?.is_empty());
+
+        Ok(())
+    })
+}
+
+// Should keep the metadata of values
+#[test]
+fn value_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let user_meta = UserMeta {
+        flags: 42,
+        content_type: Some("text/plain".to_owned()),
+    };
+
+    smol::block_on(async {
+        let before = SystemTime::now() - Duration::from_millis(1);
+        store
+            .set_with_meta("key1".to_owned(), "value1".to_owned(), user_meta.clone())
+            .await?;
+        let (value, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert_eq!(value, "value1");
+        assert_eq!(meta.user, user_meta);
+        assert_eq!(meta.created, meta.updated);
+        assert!(meta.created >= before);
+        let created = meta.created;
+        assert!(store.get_with_meta("missing".to_owned()).await?.is_none());
+
+        // Appending keeps the user metadata and a plain set resets it, while the
+        // creation time is kept until the key is removed.
+        thread::sleep(Duration::from_millis(10));
+        store.append("key1".to_owned(), "+".to_owned()).await?;
+        let (value, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert_eq!(value, "value1+");
+        assert_eq!(meta.user, user_meta);
+        assert_eq!(meta.created, created);
+        assert!(meta.updated > created);
+        store.set("key1".to_owned(), "value2".to_owned()).await?;
+        let (_, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert_eq!(meta.user, UserMeta::default());
+        assert_eq!(meta.created, created);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        store.compact().await?;
+        store
+            .set_stream("key1".to_owned(), value_stream(b"value3", 2))
+            .await?;
+        let (value, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert_eq!(value, "value3");
+        assert_eq!(meta.created, created);
+
+        store.remove("key1".to_owned()).await?;
+        store.set("key1".to_owned(), "value4".to_owned()).await?;
+        let (_, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert!(meta.created > created);
+
+        Ok(())
+    })
+}
+
+// Should stream values in and out in chunks
+#[test]
+fn stream_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    // Characters which are split across chunks or escaped in the log, spanning more
+    // than one chunk read from the log
+    let value = "plain \"quoted\" back\\slash\n\u{1}é中😀 ".repeat(5000);
+    let user_meta = UserMeta {
+        flags: 7,
+        content_type: Some("text/\"plain\"".to_owned()),
+    };
+    smol::block_on(async {
+        store
+            .set_stream("key1".to_owned(), value_stream(value.as_bytes(), 7))
+            .await?;
+        assert_eq!(store.get("key1".to_owned()).await?, Some(value.clone()));
+        let chunks = store.get_stream("key1".to_owned()).await?.unwrap();
+        assert_eq!(collect_stream(chunks).await?, value.as_bytes());
+
+        store.set("key2".to_owned(), value.clone()).await?;
+        let chunks = store.get_stream("key2".to_owned()).await?.unwrap();
+        assert_eq!(collect_stream(chunks).await?, value.as_bytes());
+
+        store
+            .set_stream("empty".to_owned(), value_stream(b"", 1))
+            .await?;
+        let chunks = store.get_stream("empty".to_owned()).await?.unwrap();
+        assert!(collect_stream(chunks).await?.is_empty());
+        assert!(store.get_stream("missing".to_owned()).await?.is_none());
+
+        // A streamed overwrite keeps the user metadata
+        store
+            .set_with_meta("key3".to_owned(), "value3".to_owned(), user_meta.clone())
+            .await?;
+        store
+            .set_stream("key3".to_owned(), value_stream(b"value4", 2))
+            .await?;
+        let (streamed, meta) = store.get_with_meta("key3".to_owned()).await?.unwrap();
+        assert_eq!(streamed, "value4");
+        assert_eq!(meta.user, user_meta);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+        let chunks = store.get_stream("key1".to_owned()).await?.unwrap();
+        assert_eq!(collect_stream(chunks).await?, value.as_bytes());
+        assert_eq!(store.get("empty".to_owned()).await?, Some(String::new()));
+        let (_, meta) = store.get_with_meta("key3".to_owned()).await?.unwrap();
+        assert_eq!(meta.user, user_meta);
+
+        Ok(())
+    })
+}
+
+// Should not set a streamed value which is invalid or aborted
+#[test]
+fn stream_invalid_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        let res = store
+            .set_stream("key1".to_owned(), value_stream(b"abc\xff", 2))
+            .await;
+        assert!(matches!(res, Err(KvsError::Utf8(_))));
+        // a character cut at the end
+        let res = store
+            .set_stream("key1".to_owned(), value_stream(&"中".as_bytes()[..2], 1))
+            .await;
+        assert!(matches!(res, Err(KvsError::Utf8(_))));
+
+        let (tx, rx) = smol::channel::unbounded();
+        tx.send(Ok(b"partial".to_vec())).await.unwrap();
+        tx.send(Err(KvsError::StringError("aborted".to_owned())))
+            .await
+            .unwrap();
+        drop(tx);
+        assert!(store.set_stream("key1".to_owned(), rx).await.is_err());
+
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        let staging_files = fs::read_dir(temp_dir.path())?
+            .filter(|entry| {
+                let entry = entry.as_ref().unwrap();
+                entry.file_name().to_string_lossy().ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(staging_files, 0);
+
+        Ok(())
+    })
+}
+
+// Stalled downloads and uploads should not keep other reads from completing
+#[test]
+fn stalled_streams() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let value = "x".repeat(1024 * 1024);
+
+    smol::block_on(async {
+        store.set("large".to_owned(), value).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+        // The receiver takes one chunk and then stops reading.
+        let chunks = store.get_stream("large".to_owned()).await?.unwrap();
+        chunks.recv().await.unwrap()?;
+        // The sender sends one chunk and then stops writing.
+        let (tx, rx) = smol::channel::unbounded();
+        tx.send(Ok(b"abc".to_vec())).await.unwrap();
+        let upload = smol::spawn({
+            let store = store.clone();
+            async move { store.set_stream("key2".to_owned(), rx).await }
+        });
+
+        let gets = async {
+            for _ in 0..10 {
+                assert_eq!(
+                    store.get("key1".to_owned()).await?,
+                    Some("value1".to_owned())
+                );
+            }
+            Ok::<_, KvsError>(true)
+        };
+        let timeout = async {
+            smol::Timer::after(Duration::from_secs(10)).await;
+            Ok::<_, KvsError>(false)
+        };
+        assert!(smol::future::or(gets, timeout).await?);
+        drop(chunks);
+        drop(tx);
+        upload.await?;
+        assert_eq!(store.get("key2".to_owned()).await?, Some("abc".to_owned()));
+
+        Ok(())
+    })
+}
+
 // Should get `None` when getting a non-existent key
 #[test]
 fn get_non_existent_value() -> Result<()> {
@@ -169,82 +411,1174 @@ fn compaction() -> Result<()> {
     })
 }
 
+// Log files not recorded in the manifest should be removed instead of replayed
 #[test]
-fn concurrent_set() -> Result<()> {
+fn orphan_log_files() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    let ex = Executor::new();
-    (0..10000).into_par_iter().for_each(|i| {
-        smol::block_on(
-            ex.run(async { store.set(format!("key{}", i), format!("value{}", i)).await }),
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(store);
+
+        // e.g. a half-written compaction file
+        let orphan_path = temp_dir.path().join("100.log");
+        fs::write(&orphan_path, r#"{"Set":{"key":"key1","value":"stale"}}"#)?;
+
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
         );
-    });
+        assert!(!orphan_path.exists());
+
+        Ok(())
+    })
+}
+
+// An unfinished compaction file should be removed instead of replayed
+#[test]
+fn unfinished_compaction_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(store);
+
+        // a crash in the middle of a compaction
+        let tmp_path = temp_dir.path().join("100.log.tmp");
+        fs::write(&tmp_path, r#"{"Set":{"key":"key1","val"#)?;
 
-    let ex = Executor::new();
-    smol::block_on(ex.run(async {
-        // We only check concurrent set in this test, so we check sequentially here
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-        for i in 0..10000 {
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert!(!tmp_path.exists());
+
+        Ok(())
+    })
+}
+
+// A directory without the manifest should be opened with all the log files
+#[test]
+fn open_without_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(store);
+
+        fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert!(temp_dir.path().join("MANIFEST").exists());
+
+        Ok(())
+    })
+}
+
+// The active log should be rolled to a new generation once it exceeds the segment size
+#[test]
+fn segment_rolling() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "x".repeat(100)).await?;
+        }
+        assert!(log_file_sizes(temp_dir.path()).len() > 10);
+        for size in log_file_sizes(temp_dir.path()) {
+            assert!(size < 1024 + 200);
+        }
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        for key_id in 0..100 {
             assert_eq!(
-                store.get(format!("key{}", i)).await?,
-                Some(format!("value{}", i))
+                store.get(format!("key{}", key_id)).await?,
+                Some("x".repeat(100))
             );
         }
 
         Ok(())
-    }))
+    })
 }
 
+// Reopening should replay the generations so that the newest command of a key wins
 #[test]
-fn concurrent_get() -> Result<()> {
+fn reopen_many_generations() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let options = KvStoreOptions {
+        max_segment_size: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
 
-    let ex = Executor::new();
-    smol::block_on(ex.run(async {
-        // We only check concurrent get in this test, so we set sequentially here
-        for i in 0..100 {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .await
-                .unwrap();
+    smol::block_on(async {
+        for iter in 0..5 {
+            for key_id in 0..100 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+            store.remove(format!("key{}", iter)).await?;
         }
-    }));
+        store.set("key0".to_owned(), "value5".to_owned()).await?;
+        let uncompacted_bytes = store.stats().await?.uncompacted_bytes;
+        assert!(log_file_sizes(temp_dir.path()).len() > 10);
 
-    let ex = Executor::new();
-    (0..100).into_par_iter().for_each(|thread_id| {
-        (0..100).into_par_iter().for_each(|i| {
-            let store = store.clone();
-            let key_id = (i + thread_id) % 100;
-            smol::block_on(ex.run(async move {
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).await?,
-                    Some(format!("value{}", key_id))
-                );
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        assert_eq!(store.stats().await?.uncompacted_bytes, uncompacted_bytes);
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value5".to_owned())
+        );
+        assert_eq!(store.get("key4".to_owned()).await?, None);
+        for key_id in (1..4).chain(5..100) {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value4".to_owned())
+            );
+        }
 
-                Result::<()>::Ok(())
-            }));
-        });
-    });
+        Ok(())
+    })
+}
 
-    // Open from disk again and check persistent data
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let ex = Executor::new();
-    (0..100).into_par_iter().for_each(|thread_id| {
-        (0..100).into_par_iter().for_each(|i| {
-            let store = store.clone();
-            let key_id = (i + thread_id) % 100;
-            smol::block_on(ex.run(async move {
-                assert_eq!(
-                    store.clone().get(format!("key{}", key_id)).await?,
-                    Some(format!("value{}", key_id))
-                );
+// Reopening should load the index snapshot and only replay the log written after it
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        snapshot_interval: 1500,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
 
-                Result::<()>::Ok(())
-            }));
-        });
-    });
+    smol::block_on(async {
+        // The second record takes the log past the interval, so the snapshot covers both.
+        let large_value = "v".repeat(1000);
+        store.set("key1".to_owned(), large_value.clone()).await?;
+        assert!(!temp_dir.path().join("INDEX").exists());
+        store.set("key1".to_owned(), large_value).await?;
+        assert!(temp_dir.path().join("INDEX").exists());
+        let snapshot = fs::read(temp_dir.path().join("INDEX"))?;
 
-    Result::<()>::Ok(())
+        // The tail written after the snapshot is replayed on open.
+        store.set("key2".to_owned(), "value3".to_owned()).await?;
+        store.set("key1".to_owned(), "value4".to_owned()).await?;
+        assert_eq!(fs::read(temp_dir.path().join("INDEX"))?, snapshot);
+        drop(store);
+
+        // The stale record is covered by the snapshot, so it is never parsed again.
+        let log_path = temp_dir.path().join("1.log");
+        let mut bytes = fs::read(&log_path)?;
+        bytes[..5].copy_from_slice(b"xxxxx");
+        fs::write(&log_path, bytes)?;
+
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        store.set("key3".to_owned(), "value5".to_owned()).await?;
+        drop(store);
+
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value5".to_owned())
+        );
+        drop(store);
+
+        fs::remove_file(temp_dir.path().join("INDEX"))?;
+        assert!(KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options).is_err());
+
+        Ok(())
+    })
+}
+
+// Salvage mode should skip corrupt records and report what was lost
+#[test]
+fn salvage_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.set("key2".to_owned(), "value3".to_owned()).await?;
+        store.set("key3".to_owned(), "value4".to_owned()).await?;
+        store.set("key3".to_owned(), "value5".to_owned()).await?;
+        drop(store);
+
+        // Break the second record of "key2" and cut the last one short.
+        let log_path = temp_dir.path().join("1.log");
+        let mut bytes = fs::read(&log_path)?;
+        let pos = find(&bytes, b"value3").unwrap();
+        bytes[pos..pos + 8].copy_from_slice(b"garbage!");
+        let pos = find(&bytes, b"value5").unwrap();
+        bytes.truncate(pos);
+        fs::write(&log_path, bytes)?;
+        assert!(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).is_err());
+
+        let options = KvStoreOptions {
+            salvage: true,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        let report = store.salvage_report().unwrap();
+        assert_eq!(report.damaged_gens, vec![1]);
+        assert_eq!(report.lost_records, 2);
+        assert_eq!(report.lost_keys, vec!["key2".to_owned(), "key3".to_owned()]);
+        assert!(temp_dir.path().join("1.log.corrupt").exists());
+        drop(store);
+
+        // The damaged generation is rewritten, so a normal open succeeds.
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert!(store.salvage_report().is_none());
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// The last versions of a key should be readable across compactions and restarts
+#[test]
+fn history_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        history_retention: HistoryRetention::Versions(3),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        for value_id in 1..=4 {
+            store
+                .set("key1".to_owned(), format!("value{}", value_id))
+                .await?;
+            store.set("key2".to_owned(), "value".to_owned()).await?;
+        }
+        let history = store.history("key1".to_owned()).await?;
+        let values: Vec<_> = history.iter().map(|v| v.value.as_str()).collect();
+        assert_eq!(values, vec!["value2", "value3", "value4"]);
+        assert!(history.windows(2).all(|w| w[0].version < w[1].version));
+        assert!(history.windows(2).all(|w| w[0].time <= w[1].time));
+        let oldest = history[0].version;
+        assert_eq!(
+            store.get_at_version("key1".to_owned(), oldest).await?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(
+            store.get_at_version("key1".to_owned(), oldest - 2).await?,
+            None
+        );
+
+        // Compaction keeps the retained versions.
+        store.compact().await?;
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+        assert_eq!(store.history("key1".to_owned()).await?, history);
+        assert_eq!(
+            store.get_at_version("key1".to_owned(), oldest).await?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+
+        store.remove("key1".to_owned()).await?;
+        assert!(store.history("key1".to_owned()).await?.is_empty());
+        assert_eq!(store.get_at_version("key1".to_owned(), oldest).await?, None);
+        drop(store);
+
+        // Only the current value is retained by default.
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        let history = store.history("key2".to_owned()).await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            store
+                .get_at_version("key2".to_owned(), history[0].version)
+                .await?,
+            Some("value".to_owned())
+        );
+        drop(store);
+
+        // The history is restored from an index snapshot.
+        let options = KvStoreOptions {
+            snapshot_interval: 1,
+            ..options
+        };
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+        store.set("key2".to_owned(), "value5".to_owned()).await?;
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        let history = store.history("key2".to_owned()).await?;
+        let values: Vec<_> = history.iter().map(|v| v.value.as_str()).collect();
+        assert_eq!(values, vec!["value", "value", "value5"]);
+
+        Ok(())
+    })
+}
+
+// Versions older than the retention window should be dropped
+#[test]
+fn history_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        history_retention: HistoryRetention::Window(Duration::from_millis(500)),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key1".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(store.history("key1".to_owned()).await?.len(), 2);
+
+        thread::sleep(Duration::from_millis(1000));
+        store.set("key1".to_owned(), "value3".to_owned()).await?;
+        let history = store.history("key1".to_owned()).await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, "value3");
+
+        // The current value is retained even if it is older than the window.
+        thread::sleep(Duration::from_millis(1000));
+        store.compact().await?;
+        assert_eq!(store.history("key1".to_owned()).await?, history);
+
+        Ok(())
+    })
+}
+
+// Retained versions moved by a partial compaction above the current value should not
+// replace it when the whole log is replayed
+#[test]
+fn history_partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 16 * 1024,
+        snapshot_interval: 0,
+        history_retention: HistoryRetention::Versions(2),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        // The old version is written to a generation which becomes garbage.
+        store.set("key".to_owned(), "value1".to_owned()).await?;
+        let mut iter = 0;
+        while !temp_dir.path().join("2.log").exists() {
+            store
+                .set(format!("hot{}", iter % 10), "x".repeat(1000))
+                .await?;
+            iter += 1;
+        }
+        // The current version is written to generations which are never compacted.
+        store.set("key".to_owned(), "value2".to_owned()).await?;
+        for key_id in 0..1000 {
+            store
+                .set(format!("cold{}", key_id), "x".repeat(100))
+                .await?;
+        }
+
+        for iter in 0..10000 {
+            store
+                .set(format!("hot{}", iter % 10), "x".repeat(1000))
+                .await?;
+            if store.stats().await?.compactions > 0 {
+                break;
+            }
+        }
+        assert!(store.stats().await?.compactions > 0);
+        assert!(!temp_dir.path().join("1.log").exists());
+        assert!(temp_dir.path().join("2.log").exists());
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        assert_eq!(
+            store.get("key".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        let history = store.history("key".to_owned()).await?;
+        let values: Vec<_> = history.iter().map(|v| v.value.as_str()).collect();
+        assert_eq!(values, vec!["value1", "value2"]);
+
+        Ok(())
+    })
+}
+
+// A tombstone kept by a compaction should count as uncompacted bytes like a written one
+#[test]
+fn kept_tombstones_not_live() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 16 * 1024,
+        snapshot_interval: 0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        // The first generation stays live, so the tombstone of its key is kept.
+        let mut key_id = 0;
+        while !temp_dir.path().join("2.log").exists() {
+            store
+                .set(format!("cold{}", key_id), "x".repeat(100))
+                .await?;
+            key_id += 1;
+        }
+        store.remove("cold0".to_owned()).await?;
+        for iter in 0..10000 {
+            store
+                .set(format!("hot{}", iter % 10), "x".repeat(1000))
+                .await?;
+            if store.stats().await?.compactions > 0 {
+                break;
+            }
+        }
+        assert!(store.stats().await?.compactions > 0);
+        assert!(temp_dir.path().join("1.log").exists());
+        assert!(!temp_dir.path().join("2.log").exists());
+        let uncompacted_bytes = store.stats().await?.uncompacted_bytes;
+
+        // Open from disk again and check the uncompacted bytes are the same
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        assert_eq!(store.get("cold0".to_owned()).await?, None);
+        assert_eq!(store.stats().await?.uncompacted_bytes, uncompacted_bytes);
+
+        Ok(())
+    })
+}
+
+// A snapshot taken before the latest compaction should be ignored
+#[test]
+fn stale_index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        snapshot_interval: 1,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+    let snapshot_path = temp_dir.path().join("INDEX");
+
+    smol::block_on(async {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), "value1".to_owned())
+                .await?;
+        }
+        let stale_snapshot = fs::read(&snapshot_path)?;
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), "value2".to_owned())
+                .await?;
+        }
+        store.compact().await?;
+        drop(store);
+
+        fs::write(&snapshot_path, stale_snapshot)?;
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value2".to_owned())
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// Reads from sealed generations should go through the shared memory maps
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 1024,
+        mmap_reads: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 4, options.clone())?;
+
+    smol::block_on(async {
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value2".to_owned())
+            );
+        }
+
+        store.compact().await?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value2".to_owned())
+            );
+        }
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 4, options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value2".to_owned())
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// Compaction output should be split into generations of the segment size
+#[test]
+fn compaction_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 64 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        for iter in 0..100 {
+            for key_id in 0..1000 {
+                store
+                    .set(
+                        format!("key{}", key_id),
+                        format!("{}{}", iter, "x".repeat(100)),
+                    )
+                    .await?;
+            }
+            if store.stats().await?.compactions > 0 {
+                // about 120 KB of live entries
+                assert!(log_file_sizes(temp_dir.path()).len() >= 2);
+                for size in log_file_sizes(temp_dir.path()) {
+                    assert!(size < 64 * 1024 + 200);
+                }
+
+                drop(store);
+                let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+                for key_id in 0..1000 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).await?,
+                        Some(format!("{}{}", iter, "x".repeat(100)))
+                    );
+                }
+                return Ok(());
+            }
+        }
+
+        panic!("No compaction detected");
+    })
+}
+
+// Compaction should only rewrite the generations with enough garbage
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 16 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        // cold keys which are never overwritten
+        for key_id in 0..1000 {
+            store
+                .set(format!("cold{}", key_id), "x".repeat(100))
+                .await?;
+        }
+        let cold_log = temp_dir.path().join("1.log");
+        let cold_log_content = fs::read(&cold_log)?;
+
+        for iter in 0..10000 {
+            store
+                .set(
+                    format!("hot{}", iter % 10),
+                    format!("{}{}", iter, "x".repeat(1000)),
+                )
+                .await?;
+            if store.stats().await?.compactions > 0 {
+                break;
+            }
+        }
+        assert!(store.stats().await?.compactions > 0);
+        assert_eq!(fs::read(&cold_log)?, cold_log_content);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("cold{}", key_id)).await?,
+                Some("x".repeat(100))
+            );
+        }
+        for key_id in 0..10 {
+            assert!(store.get(format!("hot{}", key_id)).await?.is_some());
+        }
+
+        Ok(())
+    })
+}
+
+// A removed key should not come back when its tombstone is compacted but the
+// generation containing the old value is not
+#[test]
+fn incremental_compaction_tombstone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 16 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+
+    smol::block_on(async {
+        store.set("victim".to_owned(), "value".to_owned()).await?;
+        for key_id in 0..1000 {
+            store
+                .set(format!("cold{}", key_id), "x".repeat(100))
+                .await?;
+        }
+        store.remove("victim".to_owned()).await?;
+
+        for iter in 0..10000 {
+            store
+                .set(
+                    format!("hot{}", iter % 10),
+                    format!("{}{}", iter, "x".repeat(1000)),
+                )
+                .await?;
+            if store.stats().await?.compactions > 0 {
+                break;
+            }
+        }
+        assert!(store.stats().await?.compactions > 0);
+        assert!(temp_dir.path().join("1.log").exists());
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        assert_eq!(store.get("victim".to_owned()).await?, None);
+
+        Ok(())
+    })
+}
+
+// Manual compaction should rewrite all the generations
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        for iter in 0..10 {
+            for key_id in 0..100 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+        }
+        store.remove("key0".to_owned()).await?;
+        store.flush().await?;
+        assert!(store.stats().await?.uncompacted_bytes > 0);
+
+        store.compact().await?;
+        let stats = store.stats().await?;
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.uncompacted_bytes, 0);
+        assert_eq!(stats.disk_size, stats.live_bytes);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value9".to_owned())
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// Compaction should not exceed the configured I/O rate
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_rate_limit: 512 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    smol::block_on(async {
+        // about 1.1 MB of log is read by the compaction
+        for iter in 0..1100 {
+            store
+                .set(format!("key{}", iter % 10), "x".repeat(1000))
+                .await?;
+        }
+        let stats = store.stats().await?;
+        assert_eq!(stats.compactions, 1);
+        assert!(stats.compaction_time >= Duration::from_millis(800));
+
+        Ok(())
+    })
+}
+
+// The rate limit should be adjustable while a compaction is running
+#[test]
+fn compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_rate_limit: 64 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 2, options)?;
+
+    let writer_store = store.clone();
+    let handle = thread::spawn(move || {
+        smol::block_on(async {
+            for iter in 0..1100 {
+                writer_store
+                    .set(format!("key{}", iter % 10), "x".repeat(1000))
+                    .await?;
+            }
+            Result::<()>::Ok(())
+        })
+    });
+
+    smol::block_on(async {
+        loop {
+            let stats = store.stats().await?;
+            if stats.compaction_total_bytes > 0 {
+                assert!(stats.compaction_done_bytes <= stats.compaction_total_bytes);
+                break;
+            }
+            assert!(!handle.is_finished(), "No compaction detected");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // it would take about 15 seconds at the initial rate
+        let start = Instant::now();
+        store.set_compaction_rate_limit(0).await?;
+        handle.join().unwrap()?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let stats = store.stats().await?;
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.compaction_total_bytes, 0);
+
+        Ok(())
+    })
+}
+
+// Writes should not wait for a rate limited compaction to copy the records
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    smol::block_on(async {
+        // about 0.5 MB of log, which is below the automatic compaction threshold
+        for iter in 0..500 {
+            store
+                .set(format!("key{}", iter % 10), "x".repeat(1000))
+                .await?;
+        }
+        // it would take about 30 seconds to read the log
+        store.set_compaction_rate_limit(16 * 1024).await?;
+
+        let compacting_store = store.clone();
+        let handle = thread::spawn(move || smol::block_on(compacting_store.compact()));
+        while store.stats().await?.compaction_total_bytes == 0 {
+            assert!(!handle.is_finished(), "No compaction detected");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let start = Instant::now();
+        store.set("key0".to_owned(), "value0".to_owned()).await?;
+        store.set("new".to_owned(), "value1".to_owned()).await?;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(store.stats().await?.compaction_total_bytes > 0);
+
+        store.set_compaction_rate_limit(0).await?;
+        handle.join().unwrap()?;
+        assert_eq!(store.stats().await?.compactions, 1);
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value0".to_owned())
+        );
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value0".to_owned())
+        );
+        assert_eq!(store.get("key1".to_owned()).await?, Some("x".repeat(1000)));
+        assert_eq!(
+            store.get("new".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// Should update integer values atomically
+#[test]
+fn incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        assert_eq!(store.incr("counter".to_owned(), 5).await?, 5);
+        assert_eq!(store.incr("counter".to_owned(), 2).await?, 7);
+        assert_eq!(store.decr("counter".to_owned(), 10).await?, -3);
+        assert_eq!(
+            store.get("counter".to_owned()).await?,
+            Some("-3".to_owned())
+        );
+
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert!(matches!(
+            store.incr("key1".to_owned(), 1).await,
+            Err(KvsError::NotAnInteger)
+        ));
+        assert!(matches!(
+            store.decr("counter".to_owned(), i64::MAX).await,
+            Err(KvsError::IntegerOverflow)
+        ));
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(store.incr("counter".to_owned(), 1).await?, -2);
+
+        Ok(())
+    })
+}
+
+// Concurrent increments should not lose any update
+#[test]
+fn concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+
+    let ex = Executor::new();
+    (0..1000).into_par_iter().for_each(|_| {
+        smol::block_on(ex.run(async { store.incr("counter".to_owned(), 1).await })).unwrap();
+    });
+
+    smol::block_on(async {
+        assert_eq!(
+            store.get("counter".to_owned()).await?,
+            Some("1000".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// Should append to existent and non-existent values
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        assert_eq!(
+            store.append("key1".to_owned(), "foo".to_owned()).await?,
+            "foo"
+        );
+        assert_eq!(
+            store.append("key1".to_owned(), "bar".to_owned()).await?,
+            "foobar"
+        );
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("foobar".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// Cache mode should evict the least recently used keys beyond the capacity
+#[test]
+fn cache_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let capacity = 10_000;
+    let options = KvStoreOptions {
+        cache_capacity: capacity,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options.clone())?;
+    let value = "v".repeat(200);
+
+    smol::block_on(async {
+        store.set("cold".to_owned(), value.clone()).await?;
+        store.set("hot".to_owned(), value.clone()).await?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value.clone()).await?;
+            assert!(store.get("hot".to_owned()).await?.is_some());
+        }
+
+        let stats = store.stats().await?;
+        assert!(stats.evictions > 0);
+        assert!(stats.live_bytes <= capacity);
+        assert_eq!(stats.keys + stats.evictions, 102);
+        assert_eq!(store.get("cold".to_owned()).await?, None);
+        assert_eq!(store.get("hot".to_owned()).await?, Some(value.clone()));
+        assert_eq!(store.get("key99".to_owned()).await?, Some(value.clone()));
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+
+        // Open from disk again and check the evictions are persistent
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+        assert_eq!(store.get("cold".to_owned()).await?, None);
+        assert_eq!(store.get("hot".to_owned()).await?, Some(value.clone()));
+        assert!(store.stats().await?.live_bytes <= capacity);
+
+        Ok(())
+    })
+}
+
+// Stats should reflect the keys and the compactions
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        let stats = store.stats().await?;
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.compactions, 0);
+
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.set("key1".to_owned(), "value3".to_owned()).await?;
+        let stats = store.stats().await?;
+        assert_eq!(stats.keys, 2);
+        assert!(stats.live_bytes > 0);
+        assert!(stats.uncompacted_bytes > 0);
+        assert_eq!(stats.generations, 1);
+        assert!(stats.disk_size >= stats.live_bytes + stats.uncompacted_bytes);
+
+        store.remove("key2".to_owned()).await?;
+        assert_eq!(store.stats().await?.keys, 1);
+
+        for iter in 0..1000 {
+            for key_id in 0..100 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        let stats = store.stats().await?;
+        assert_eq!(stats.keys, 100);
+        assert!(stats.compactions > 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+
+    let ex = Executor::new();
+    (0..10000).into_par_iter().for_each(|i| {
+        smol::block_on(
+            ex.run(async { store.set(format!("key{}", i), format!("value{}", i)).await }),
+        );
+    });
+
+    let ex = Executor::new();
+    smol::block_on(ex.run(async {
+        // We only check concurrent set in this test, so we check sequentially here
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..10000 {
+            assert_eq!(
+                store.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+
+        Ok(())
+    }))
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+
+    let ex = Executor::new();
+    smol::block_on(ex.run(async {
+        // We only check concurrent get in this test, so we set sequentially here
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await
+                .unwrap();
+        }
+    }));
+
+    let ex = Executor::new();
+    (0..100).into_par_iter().for_each(|thread_id| {
+        (0..100).into_par_iter().for_each(|i| {
+            let store = store.clone();
+            let key_id = (i + thread_id) % 100;
+            smol::block_on(ex.run(async move {
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).await?,
+                    Some(format!("value{}", key_id))
+                );
+
+                Result::<()>::Ok(())
+            }));
+        });
+    });
+
+    // Open from disk again and check persistent data
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let ex = Executor::new();
+    (0..100).into_par_iter().for_each(|thread_id| {
+        (0..100).into_par_iter().for_each(|i| {
+            let store = store.clone();
+            let key_id = (i + thread_id) % 100;
+            smol::block_on(ex.run(async move {
+                assert_eq!(
+                    store.clone().get(format!("key{}", key_id)).await?,
+                    Some(format!("value{}", key_id))
+                );
+
+                Result::<()>::Ok(())
+            }));
+        });
+    });
+
+    Result::<()>::Ok(())
+}
+
+// Reads should wait for a free reader when there are more concurrent reads than readers
+#[test]
+fn concurrent_get_beyond_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_readers: 2,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<NaiveThreadPool>::open_with(temp_dir.path(), 2, options)?;
+
+    let ex = Executor::new();
+    smol::block_on(ex.run(async {
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+
+        let tasks: Vec<_> = (0..1000)
+            .map(|i| {
+                let store = store.clone();
+                ex.spawn(async move { store.get(format!("key{}", i % 100)).await })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await?, Some(format!("value{}", i % 100)));
+        }
+
+        Ok(())
+    }))
+}
+
+// Returns a stream of the given bytes in chunks of `chunk_len` bytes.
+fn value_stream(bytes: &[u8], chunk_len: usize) -> ValueStream {
+    let (tx, rx) = smol::channel::unbounded();
+    for chunk in bytes.chunks(chunk_len) {
+        tx.try_send(Ok(chunk.to_vec())).unwrap();
+    }
+    rx
+}
+
+// Collects the chunks of a stream into one buffer.
+async fn collect_stream(chunks: ValueStream) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Ok(chunk) = chunks.recv().await {
+        bytes.extend(chunk?);
+    }
+    Ok(bytes)
+}
+
+// Returns the offset of the first occurrence of the pattern in the bytes.
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+// Returns the sizes of the log files in the given directory.
+fn log_file_sizes(dir: &Path) -> Vec<u64> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(&path).unwrap().len())
+        .collect()
 }
@@ -0,0 +1,202 @@
+use smol::channel;
+
+use kvs::{
+    CacheLayer, Identity, KvsEngine, KvsError, LimitsLayer, LoggingLayer, MemKvsEngine, Metrics,
+    MetricsLayer, OpStats, Result, Stack,
+};
+
+fn op_stats(engine: &Metrics<impl KvsEngine>, op: &str) -> OpStats {
+    engine
+        .snapshot()
+        .into_iter()
+        .find(|stats| stats.op == op)
+        .unwrap_or_default()
+}
+
+// Should count the requests and failures of each operation and report them in stats
+#[test]
+fn metrics() -> Result<()> {
+    let engine = MemKvsEngine::new().with_layer(MetricsLayer);
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        engine.get("key1".to_owned()).await?;
+        engine.get("key2".to_owned()).await?;
+        assert!(engine.remove("key2".to_owned()).await.is_err());
+
+        let get = op_stats(&engine, "get");
+        assert_eq!((get.requests, get.errors), (2, 0));
+        let remove = op_stats(&engine, "remove");
+        assert_eq!((remove.requests, remove.errors), (1, 1));
+
+        let stats = engine.stats().await?;
+        assert_eq!(stats.keys, 1);
+        let ops: Vec<_> = stats.ops.iter().map(|stats| stats.op.as_str()).collect();
+        assert_eq!(ops, ["get", "remove", "set", "stats"]);
+
+        Ok(())
+    })
+}
+
+// Should reject writes of keys and values larger than the limits
+#[test]
+fn limits() -> Result<()> {
+    let engine = MemKvsEngine::new().with_layer(LimitsLayer {
+        max_key_size: Some(4),
+        max_value_size: Some(8),
+    });
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "12345678".to_owned()).await?;
+        assert!(matches!(
+            engine.set("key10".to_owned(), "value".to_owned()).await,
+            Err(KvsError::TooLarge("Key", 5, 4))
+        ));
+        assert!(matches!(
+            engine.set("key2".to_owned(), "123456789".to_owned()).await,
+            Err(KvsError::TooLarge("Value", 9, 8))
+        ));
+        assert!(matches!(
+            engine.incr("key10".to_owned(), 1).await,
+            Err(KvsError::TooLarge("Key", 5, 4))
+        ));
+        assert_eq!(engine.get("key10".to_owned()).await?, None);
+
+        // A streamed value is cut off once it exceeds the limit
+        let (tx, rx) = channel::unbounded();
+        for _ in 0..3 {
+            tx.send(Ok(b"abcd".to_vec())).await.unwrap();
+        }
+        drop(tx);
+        assert!(matches!(
+            engine.set_stream("key3".to_owned(), rx).await,
+            Err(KvsError::TooLarge("Value", 12, 8))
+        ));
+        assert_eq!(engine.get("key3".to_owned()).await?, None);
+
+        let (tx, rx) = channel::unbounded();
+        tx.send(Ok(b"abcd".to_vec())).await.unwrap();
+        drop(tx);
+        engine.set_stream("key3".to_owned(), rx).await?;
+        assert_eq!(
+            engine.get("key3".to_owned()).await?,
+            Some("abcd".to_owned())
+        );
+
+        // Appends under the limit are rejected once the value would exceed it
+        engine.append("key4".to_owned(), "12345".to_owned()).await?;
+        assert!(matches!(
+            engine.append("key4".to_owned(), "6789".to_owned()).await,
+            Err(KvsError::TooLarge("Value", 9, 8))
+        ));
+        assert_eq!(
+            engine.append("key4".to_owned(), "678".to_owned()).await?,
+            "12345678"
+        );
+
+        Ok(())
+    })
+}
+
+// Should answer repeated reads from the cache and invalidate the written keys
+#[test]
+fn read_cache() -> Result<()> {
+    let inner = MemKvsEngine::new().with_layer(MetricsLayer);
+    let engine = inner.clone().with_layer(CacheLayer::new(1024));
+    let inner_gets = || op_stats(&inner, "get").requests;
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        for _ in 0..3 {
+            assert_eq!(
+                engine.get("key1".to_owned()).await?,
+                Some("value1".to_owned())
+            );
+        }
+        assert_eq!(inner_gets(), 1);
+
+        // Every write invalidates the cached value
+        engine.set("key1".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            engine.get("key1".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        engine.append("key1".to_owned(), "0".to_owned()).await?;
+        assert_eq!(
+            engine.get("key1".to_owned()).await?,
+            Some("value20".to_owned())
+        );
+        engine.remove("key1".to_owned()).await?;
+        assert_eq!(engine.get("key1".to_owned()).await?, None);
+        assert_eq!(inner_gets(), 4);
+
+        // Only the values which are not cached are read by get_many
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        engine.set("key2".to_owned(), "value2".to_owned()).await?;
+        engine.get("key1".to_owned()).await?;
+        let keys = vec!["key1".to_owned(), "key2".to_owned(), "key3".to_owned()];
+        assert_eq!(
+            engine.get_many(keys.clone()).await?,
+            [Some("value1".to_owned()), Some("value2".to_owned()), None]
+        );
+        assert_eq!(op_stats(&inner, "get_many").requests, 1);
+        engine.get_many(keys).await?;
+        assert_eq!(op_stats(&inner, "get_many").requests, 2);
+        assert_eq!(inner_gets(), 5);
+
+        Ok(())
+    })
+}
+
+// Should evict the least recently used values beyond the capacity
+#[test]
+fn read_cache_eviction() -> Result<()> {
+    let inner = MemKvsEngine::new().with_layer(MetricsLayer);
+    // Room for two keys of 4 bytes with values of 6 bytes
+    let engine = inner.clone().with_layer(CacheLayer::new(25));
+    let inner_gets = || op_stats(&inner, "get").requests;
+
+    smol::block_on(async {
+        for key_id in 1..=3 {
+            let value = format!("value{}", key_id);
+            engine.set(format!("key{}", key_id), value).await?;
+        }
+        engine.get("key1".to_owned()).await?;
+        engine.get("key2".to_owned()).await?;
+        engine.get("key1".to_owned()).await?;
+        assert_eq!(inner_gets(), 2);
+
+        // key2 is the least recently used one
+        engine.get("key3".to_owned()).await?;
+        engine.get("key1".to_owned()).await?;
+        assert_eq!(inner_gets(), 3);
+        engine.get("key2".to_owned()).await?;
+        assert_eq!(inner_gets(), 4);
+
+        Ok(())
+    })
+}
+
+// Layers should compose with Stack, Option and Identity
+#[test]
+fn compose_layers() -> Result<()> {
+    let layers = Stack::new(
+        Stack::new(Identity, None::<CacheLayer>),
+        Some(LoggingLayer::default()),
+    );
+    let engine = MemKvsEngine::new()
+        .with_layer(layers)
+        .with_layer(MetricsLayer);
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            engine.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(engine.incr("count".to_owned(), 2).await?, 2);
+        assert_eq!(op_stats(&engine, "set").requests, 1);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,296 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvsError, LsmOptions, LsmStore, MemVfs, RayonThreadPool, Result, UserMeta};
+
+// Options which flush the memtable and compact the levels after a few writes
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 1024,
+        block_size: 256,
+        level0_table_limit: 2,
+        table_size: 2048,
+        level_size_base: 4096,
+        level_size_multiplier: 2,
+        ..LsmOptions::default()
+    }
+}
+
+fn open(path: &Path) -> Result<LsmStore<RayonThreadPool>> {
+    LsmStore::open_with(path, 1, small_options())
+}
+
+// Should get previously stored values from the write-ahead log and the tables
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.set("key1".to_owned(), "value3".to_owned()).await?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(store.get("missing".to_owned()).await?, None);
+
+        // Open from disk again, replaying the log
+        drop(store);
+        let store = LsmStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+
+        // Open from disk again after writing the memtable to a table
+        store.compact().await?;
+        drop(store);
+        let store = LsmStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(store.get("missing".to_owned()).await?, None);
+
+        Ok(())
+    })
+}
+
+// Should remove keys in the memtable and in the tables
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.compact().await?;
+        store.remove("key1".to_owned()).await?;
+        store.set("key3".to_owned(), "value3".to_owned()).await?;
+        store.remove("key3".to_owned()).await?;
+        assert!(matches!(
+            store.remove("key1".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(store.get("key3".to_owned()).await?, None);
+
+        // The removals shadow the table until a compaction drops both
+        drop(store);
+        let store = open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        store.compact().await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(store.stats().await?.keys, 1);
+
+        Ok(())
+    })
+}
+
+// Writes beyond the memtable should be flushed to tables and compacted down the levels
+// while every key stays readable
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    smol::block_on(async {
+        for round in 0..3 {
+            for key_id in 0..500 {
+                let value = format!("value{}_{}", key_id, round);
+                store.set(format!("key{}", key_id), value).await?;
+            }
+        }
+        for key_id in (0..500).step_by(5) {
+            store.remove(format!("key{}", key_id)).await?;
+        }
+
+        let check = |store: LsmStore<RayonThreadPool>| async move {
+            for key_id in 0..500 {
+                let expected = if key_id % 5 == 0 {
+                    None
+                } else {
+                    Some(format!("value{}_2", key_id))
+                };
+                assert_eq!(store.get(format!("key{}", key_id)).await?, expected);
+            }
+            Result::<()>::Ok(())
+        };
+        check(store.clone()).await?;
+
+        let stats = store.stats().await?;
+        assert_eq!(stats.keys, 400);
+        assert!(stats.compactions > 0);
+        assert!(stats.generations > 1);
+        assert_eq!(stats.generations as usize, table_files(temp_dir.path()));
+        let disk_size = stats.disk_size;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(store.clone()).await?;
+
+        // A full compaction drops the overwritten values and the removals
+        store.compact().await?;
+        check(store.clone()).await?;
+        let stats = store.stats().await?;
+        assert_eq!(stats.keys, 400);
+        assert!(stats.disk_size < disk_size);
+
+        Ok(())
+    })
+}
+
+// Should keep the metadata of values across memtable flushes
+#[test]
+fn value_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let user_meta = UserMeta {
+        flags: 42,
+        content_type: Some("text/plain".to_owned()),
+    };
+
+    smol::block_on(async {
+        store
+            .set_with_meta("key1".to_owned(), "1".to_owned(), user_meta.clone())
+            .await?;
+        let (_, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        let created = meta.created;
+        store.compact().await?;
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(store.incr("key1".to_owned(), 2).await?, 3);
+        let (value, meta) = store.get_with_meta("key1".to_owned()).await?.unwrap();
+        assert_eq!(value, "3");
+        assert_eq!(meta.user, user_meta);
+        assert_eq!(meta.created, created);
+        assert!(meta.updated > created);
+        assert_eq!(store.append("key1".to_owned(), "0".to_owned()).await?, "30");
+        assert_eq!(store.decr("key1".to_owned(), 5).await?, 25);
+
+        Ok(())
+    })
+}
+
+// A record cut short at the end of the write-ahead log should be dropped
+#[test]
+fn torn_log_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(store);
+        let wal = fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("wal".as_ref()))
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&wal)?
+            .write_all(br#"["key2",{"value":"val"#)?;
+
+        let store = open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        store.set("key3".to_owned(), "value3".to_owned()).await?;
+
+        drop(store);
+        let store = open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// A write which fails halfway should not keep the following writes from being replayed
+#[test]
+fn failed_write() -> Result<()> {
+    let vfs = MemVfs::new();
+    let options = LsmOptions {
+        vfs: Arc::new(vfs.clone()),
+        ..LsmOptions::default()
+    };
+    let store = LsmStore::<RayonThreadPool>::open_with("/db", 1, options.clone())?;
+
+    smol::block_on(async {
+        vfs.set_capacity(Some(1024));
+        let mut written = 0;
+        while store
+            .set(format!("key{}", written), "x".repeat(100))
+            .await
+            .is_ok()
+        {
+            written += 1;
+        }
+        vfs.set_capacity(None);
+        store.set("after".to_owned(), "value".to_owned()).await?;
+
+        drop(store);
+        let store = LsmStore::<RayonThreadPool>::open_with("/db", 1, options)?;
+        for key_id in 0..written {
+            assert!(store.get(format!("key{}", key_id)).await?.is_some());
+        }
+        assert_eq!(store.get(format!("key{}", written)).await?, None);
+        assert_eq!(
+            store.get("after".to_owned()).await?,
+            Some("value".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// Table files not recorded in the manifest should be removed
+#[test]
+fn orphan_table_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    drop(store);
+
+    let orphan = temp_dir.path().join("100.sst");
+    fs::write(&orphan, "unfinished compaction output")?;
+    let store = open(temp_dir.path())?;
+    assert!(!orphan.exists());
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        Ok(())
+    })
+}
+
+fn table_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("sst".as_ref()))
+        .count()
+}
@@ -0,0 +1,66 @@
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvsError, MemKvsEngine, Result, UserMeta};
+
+// The memory engine should support the whole trait and reload its snapshot
+#[test]
+fn memory_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("memory.snapshot");
+    let engine = MemKvsEngine::open(&path)?;
+    let user_meta = UserMeta {
+        flags: 7,
+        content_type: Some("text/plain".to_owned()),
+    };
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        engine
+            .set_with_meta("key2".to_owned(), "value2".to_owned(), user_meta.clone())
+            .await?;
+        assert_eq!(engine.incr("count".to_owned(), 5).await?, 5);
+        assert_eq!(
+            engine.append("key2".to_owned(), "+".to_owned()).await?,
+            "value2+"
+        );
+        assert!(matches!(
+            engine.incr("key1".to_owned(), 1).await,
+            Err(KvsError::NotAnInteger)
+        ));
+        engine.remove("key1".to_owned()).await?;
+        assert!(matches!(
+            engine.remove("key1".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(
+            engine
+                .get_many(vec!["key1".to_owned(), "key2".to_owned()])
+                .await?,
+            vec![None, Some("value2+".to_owned())]
+        );
+        let stats = engine.stats().await?;
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.live_bytes, 5 + 1 + 4 + 7);
+
+        // Nothing is written until the engine is flushed or dropped
+        assert!(!path.exists());
+        engine.flush().await?;
+        assert!(path.exists());
+        engine.set("key3".to_owned(), "value3".to_owned()).await?;
+
+        // Open from the snapshot again and check persistent data
+        drop(engine);
+        let engine = MemKvsEngine::open(&path)?;
+        assert_eq!(engine.get("count".to_owned()).await?, Some("5".to_owned()));
+        assert_eq!(
+            engine.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        let (value, meta) = engine.get_with_meta("key2".to_owned()).await?.unwrap();
+        assert_eq!(value, "value2+");
+        assert_eq!(meta.user, user_meta);
+        assert!(meta.updated >= meta.created);
+
+        Ok(())
+    })
+}
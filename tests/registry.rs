@@ -0,0 +1,110 @@
+use tempfile::TempDir;
+
+use kvs::{
+    BoxedEngine, EngineConfig, EngineRegistry, KvsEngine, KvsError, MemKvsEngine, MetricsLayer,
+    Result,
+};
+
+// Every engine of the default registry should be opened by its name
+#[test]
+fn open_builtin_engines() -> Result<()> {
+    let registry = EngineRegistry::default();
+    let names: Vec<_> = registry.names().collect();
+    assert_eq!(names, ["kvs", "lsm", "memory", "sled"]);
+
+    for name in names {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = EngineConfig::new(temp_dir.path(), 1);
+        let engine = registry.open(name, &config)?;
+
+        smol::block_on(async {
+            engine.set("key1".to_owned(), "value1".to_owned()).await?;
+            assert_eq!(
+                engine.get("key1".to_owned()).await?,
+                Some("value1".to_owned())
+            );
+            engine.flush().await?;
+            assert_eq!(engine.stats().await?.keys, 1);
+            Result::<()>::Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+// The settings of the kvs and memory engines should be rejected by the other engines
+#[test]
+fn unsupported_config() -> Result<()> {
+    let registry = EngineRegistry::default();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = EngineConfig {
+        cache_capacity: Some(1024),
+        ..EngineConfig::new(temp_dir.path(), 1)
+    };
+
+    registry.open("kvs", &config)?;
+    for name in &["sled", "memory", "lsm"] {
+        assert!(matches!(
+            registry.open(name, &config),
+            Err(KvsError::Unsupported("cache mode"))
+        ));
+    }
+
+    let config = EngineConfig {
+        memory_snapshot: true,
+        ..EngineConfig::new(temp_dir.path(), 1)
+    };
+    registry.open("memory", &config)?;
+    for name in &["kvs", "sled", "lsm"] {
+        assert!(matches!(
+            registry.open(name, &config),
+            Err(KvsError::Unsupported("memory snapshot"))
+        ));
+    }
+
+    Ok(())
+}
+
+// Engines registered by the user should be opened like the builtin ones
+#[test]
+fn register_engine() -> Result<()> {
+    let mut registry = EngineRegistry::new();
+    assert!(matches!(
+        registry.open("metered", &EngineConfig::new(".", 1)),
+        Err(KvsError::UnknownEngine(name)) if name == "metered"
+    ));
+
+    registry.register("metered", |_| {
+        Ok(MemKvsEngine::new().with_layer(MetricsLayer))
+    });
+    assert!(registry.contains("metered"));
+    let engine = registry.open("metered", &EngineConfig::new(".", 1))?;
+
+    smol::block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        let stats = engine.stats().await?;
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.ops[0].op, "set");
+
+        Ok(())
+    })
+}
+
+// Engines of different types should be handled as the same type once boxed
+#[test]
+fn boxed_engines() -> Result<()> {
+    let engines = vec![
+        BoxedEngine::new(MemKvsEngine::new()),
+        BoxedEngine::new(MemKvsEngine::new().with_layer(MetricsLayer)),
+    ];
+
+    smol::block_on(async {
+        for engine in engines {
+            assert_eq!(engine.incr("count".to_owned(), 2).await?, 2);
+            assert_eq!(engine.clone().decr("count".to_owned(), 3).await?, -1);
+            assert_eq!(engine.get("count".to_owned()).await?, Some("-1".to_owned()));
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,376 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, MemVfs, RayonThreadPool, Result, Vfs};
+
+const DIR: &str = "/db";
+
+fn open(vfs: &MemVfs) -> Result<KvStore<RayonThreadPool>> {
+    let options = KvStoreOptions {
+        vfs: Arc::new(vfs.clone()),
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with(DIR, 1, options)
+}
+
+// Data written through a MemVfs should be readable after reopening
+#[test]
+fn mem_vfs_reopen() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.remove("key1".to_owned()).await?;
+
+        drop(store);
+        let store = open(&vfs)?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// A crash should keep the synced data and at most a part of the data which is not synced
+#[test]
+fn crash_drops_unsynced_data() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.flush().await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+
+        vfs.crash();
+        drop(store);
+        let store = open(&vfs)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        let value = store.get("key2".to_owned()).await?;
+        assert!(value.is_none() || value == Some("value2".to_owned()));
+
+        Ok(())
+    })
+}
+
+// A record torn by a crash should be truncated from the active log on open
+#[test]
+fn crash_tears_unsynced_record() -> Result<()> {
+    let log_path = Path::new(DIR).join("1.log");
+    let mut torn = 0;
+    for seed in 0..20 {
+        let vfs = MemVfs::with_seed(seed);
+        let store = open(&vfs)?;
+
+        smol::block_on(async {
+            store.set("key1".to_owned(), "value1".to_owned()).await?;
+            store.flush().await?;
+            let synced_len = vfs.file_size(&log_path)?;
+            store.set("key2".to_owned(), "x".repeat(1000)).await?;
+            let written_len = vfs.file_size(&log_path)?;
+
+            vfs.crash();
+            drop(store);
+            let crashed_len = vfs.file_size(&log_path)?;
+            assert!(synced_len <= crashed_len && crashed_len <= written_len);
+            if synced_len < crashed_len && crashed_len < written_len {
+                torn += 1;
+            }
+            let store = open(&vfs)?;
+            assert_eq!(
+                store.get("key1".to_owned()).await?,
+                Some("value1".to_owned())
+            );
+            let value = store.get("key2".to_owned()).await?;
+            assert_eq!(value.is_some(), crashed_len == written_len);
+            store.set("key3".to_owned(), "value3".to_owned()).await?;
+
+            // The torn generation is not the active one when it is replayed again.
+            drop(store);
+            let store = open(&vfs)?;
+            assert_eq!(
+                store.get("key3".to_owned()).await?,
+                Some("value3".to_owned())
+            );
+
+            Ok::<_, KvsError>(())
+        })?;
+    }
+    assert!(torn > 0);
+
+    Ok(())
+}
+
+// A crash between the writes of a compaction and their sync should not lose any data
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        for iter in 0..10 {
+            for key_id in 0..20 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+        }
+        store.remove("key0".to_owned()).await?;
+        store.flush().await?;
+
+        // The output of compacting the first generation is the second one.
+        vfs.crash_on_sync(Some(Path::new(DIR).join("2.log.tmp")));
+        assert!(store.compact().await.is_err());
+        assert!(vfs.crashed());
+
+        drop(store);
+        vfs.recover();
+        let store = open(&vfs)?;
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+        for key_id in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value9".to_owned())
+            );
+        }
+
+        // A compaction after the recovery should succeed
+        store.compact().await?;
+        drop(store);
+        let store = open(&vfs)?;
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+        for key_id in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value9".to_owned())
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// A file which is not synced into its directory should disappear after a crash
+#[test]
+fn crash_drops_unsynced_entries() -> Result<()> {
+    let vfs = MemVfs::new();
+    let dir = Path::new(DIR);
+    vfs.create_dir_all(dir)?;
+
+    let mut file = vfs.create(&dir.join("synced"))?;
+    file.write_all(b"data")?;
+    file.sync_all()?;
+    vfs.sync_dir(dir)?;
+    let mut file = vfs.create(&dir.join("unsynced"))?;
+    file.write_all(b"data")?;
+    file.sync_all()?;
+    vfs.rename(&dir.join("synced"), &dir.join("renamed"))?;
+
+    vfs.crash();
+    assert_eq!(vfs.read_dir(dir)?, vec![dir.join("synced")]);
+    let mut data = Vec::new();
+    vfs.open(&dir.join("synced"))?.read_to_end(&mut data)?;
+    assert_eq!(data, b"data");
+
+    Ok(())
+}
+
+// Writes should fail once the disk is full, leaving the written data readable
+#[test]
+fn disk_full() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        store.set("key0".to_owned(), "value0".to_owned()).await?;
+        vfs.set_capacity(Some(1024));
+        let mut res = Ok(());
+        for key_id in 1..100 {
+            res = store.set(format!("key{}", key_id), "x".repeat(100)).await;
+            if res.is_err() {
+                break;
+            }
+        }
+        assert!(res.is_err());
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value0".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// A write failure should make the store read-only until it is recovered
+#[test]
+fn read_only_after_write_failure() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        vfs.set_capacity(Some(200));
+        assert!(store
+            .set("key2".to_owned(), "x".repeat(1000))
+            .await
+            .is_err());
+        assert!(store.stats().await?.read_only);
+
+        // Writes are rejected even if they fit, while reads are still served.
+        vfs.set_capacity(None);
+        assert!(matches!(
+            store.set("key3".to_owned(), "value3".to_owned()).await,
+            Err(KvsError::ReadOnly(_))
+        ));
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        store.recover().await?;
+        assert!(!store.stats().await?.read_only);
+        store.set("key3".to_owned(), "value3".to_owned()).await?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open(&vfs)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+
+        Ok(())
+    })
+}
+
+// The partial record of a failed write should not break reopening without a recovery
+#[test]
+fn reopen_after_write_failure() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        vfs.set_capacity(Some(200));
+        assert!(store
+            .set("key2".to_owned(), "x".repeat(1000))
+            .await
+            .is_err());
+
+        drop(store);
+        vfs.set_capacity(None);
+        let store = open(&vfs)?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+
+        Ok(())
+    })
+}
+
+// Injected write and sync failures should be returned to the caller
+#[test]
+fn injected_failures() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        vfs.set_fail_syncs(true);
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert!(store.flush().await.is_err());
+        vfs.set_fail_syncs(false);
+        store.flush().await?;
+
+        vfs.set_fail_writes(true);
+        assert!(store
+            .set("key2".to_owned(), "value2".to_owned())
+            .await
+            .is_err());
+        assert!(open(&vfs).is_err());
+
+        Ok(())
+    })
+}
+
+// A failed compaction read should not turn the store read-only
+#[test]
+fn compaction_read_failure() -> Result<()> {
+    let vfs = MemVfs::new();
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        for iter in 0..10 {
+            for key_id in 0..20 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+        }
+
+        vfs.set_fail_reads(true);
+        assert!(store.compact().await.is_err());
+        vfs.set_fail_reads(false);
+        assert!(!store.stats().await?.read_only);
+        store.set("key0".to_owned(), "value10".to_owned()).await?;
+        store.compact().await?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open(&vfs)?;
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value10".to_owned())
+        );
+        for key_id in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value9".to_owned())
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// Short writes should be retried until the whole record is written
+#[test]
+fn short_writes() -> Result<()> {
+    let vfs = MemVfs::new();
+    vfs.set_short_writes(true);
+    let store = open(&vfs)?;
+
+    smol::block_on(async {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .await?;
+        }
+
+        drop(store);
+        let store = open(&vfs)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some(format!("value{}", key_id))
+            );
+        }
+
+        Ok(())
+    })
+}